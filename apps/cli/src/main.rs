@@ -0,0 +1,522 @@
+// svault: command-line client for vault files, for scripts and terminals.
+//
+// Secrets are prompted for on the terminal without echo. With --password-stdin
+// they are read from stdin instead, one line each, in the order they would be
+// prompted for. Data goes to stdout, as JSON with --json; notices go to stderr.
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use core_crypto::{calibrate_argon2, generate_password, generate_pronounceable_password, CipherSuite, PasswordRules};
+use rand::RngCore;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+use uuid::Uuid;
+use vault_store::{
+    Credential, KdfPolicy, KeySlot, UnlockOptions, UnlockedVault, VaultEntry, VaultError, VaultHeader, VaultStore,
+};
+use zeroize::Zeroizing;
+
+// Most memory the KDF of a new vault may use, as in the desktop app
+const MAX_KDF_MEM_KIB: u32 = 1024 * 1024;
+
+#[derive(Parser)]
+#[command(name = "svault", version, about = "Read and edit vault files from the command line")]
+struct Cli {
+    /// Vault file
+    #[arg(long, global = true, env = "SVAULT_PATH")]
+    vault: Option<PathBuf>,
+
+    /// Keyfile required by the vault, or to require for `init`
+    #[arg(long, global = true)]
+    keyfile: Option<PathBuf>,
+
+    /// Read the master password and any other secret from stdin, one per line
+    #[arg(long, global = true)]
+    password_stdin: bool,
+
+    /// Print results as JSON
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// Cut off a record left half-written by an interrupted save when unlocking
+    #[arg(long, global = true)]
+    recover: bool,
+
+    /// Upgrade a vault made before headers were signed (format v5 and older)
+    /// when unlocking, accepting its header as it is
+    #[arg(long, global = true)]
+    migrate: bool,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Create a new vault
+    Init {
+        #[arg(long, value_enum, default_value_t = Cipher::Aes256Gcm)]
+        cipher: Cipher,
+
+        /// How long unlocking should take on this machine, in milliseconds
+        #[arg(long, default_value_t = 1000)]
+        kdf_ms: u64,
+
+        /// Most memory the KDF may use, in MiB
+        #[arg(long, default_value_t = MAX_KDF_MEM_KIB / 1024)]
+        kdf_max_mib: u32,
+    },
+    /// Add an entry; its password is prompted for unless --generate is given
+    Add {
+        title: String,
+
+        #[command(flatten)]
+        fields: EntryFields,
+
+        /// Generate a password of this length instead (default 16)
+        #[arg(long, value_name = "LENGTH", num_args = 0..=1, default_missing_value = "16")]
+        generate: Option<usize>,
+    },
+    /// Show an entry, or one field of it
+    Get {
+        /// Entry id or title
+        entry: String,
+
+        /// Print only this field: title, username, password, url, notes or a custom field name
+        #[arg(long)]
+        field: Option<String>,
+    },
+    /// Change an entry; fields not given are kept
+    Edit {
+        /// Entry id or title
+        entry: String,
+
+        #[arg(long)]
+        title: Option<String>,
+
+        #[command(flatten)]
+        fields: EntryFields,
+
+        /// Prompt for a new password
+        #[arg(long, conflicts_with = "generate")]
+        password: bool,
+
+        /// Generate a new password of this length (default 16)
+        #[arg(long, value_name = "LENGTH", num_args = 0..=1, default_missing_value = "16")]
+        generate: Option<usize>,
+    },
+    /// Move an entry to the trash
+    Rm {
+        /// Entry id or title
+        entry: String,
+    },
+    /// List entries by title
+    Ls,
+    /// Search titles, usernames, URLs, notes and tags, best match first
+    Search { query: String },
+    /// Print a random password; needs no vault
+    Generate {
+        #[arg(long, default_value_t = 16)]
+        length: usize,
+
+        #[arg(long)]
+        no_symbols: bool,
+
+        /// Letters only in consonant-vowel syllables, easier to read out
+        #[arg(long, conflicts_with = "no_symbols")]
+        pronounceable: bool,
+    },
+    /// Change the master password
+    Passwd,
+    /// Rewrite the vault without superseded versions and purged trash
+    Compact,
+}
+
+// --url and --tag replace the entry's lists when given at least once
+#[derive(Args)]
+struct EntryFields {
+    #[arg(short, long)]
+    username: Option<String>,
+
+    #[arg(long = "url")]
+    urls: Vec<String>,
+
+    #[arg(long)]
+    notes: Option<String>,
+
+    #[arg(long = "tag")]
+    tags: Vec<String>,
+}
+
+impl EntryFields {
+    fn apply(self, entry: &mut VaultEntry) {
+        if let Some(username) = self.username {
+            entry.username = username;
+        }
+        if !self.urls.is_empty() {
+            entry.urls = self.urls;
+        }
+        if let Some(notes) = self.notes {
+            entry.notes = notes;
+        }
+        if !self.tags.is_empty() {
+            entry.tags = self.tags;
+        }
+    }
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+enum Cipher {
+    #[value(name = "aes-256-gcm")]
+    Aes256Gcm,
+    #[value(name = "xchacha20-poly1305")]
+    XChaCha20Poly1305,
+}
+
+impl From<Cipher> for CipherSuite {
+    fn from(c: Cipher) -> Self {
+        match c {
+            Cipher::Aes256Gcm => CipherSuite::Aes256Gcm,
+            Cipher::XChaCha20Poly1305 => CipherSuite::XChaCha20Poly1305,
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Listed {
+    id: Uuid,
+    title: String,
+}
+
+#[derive(Serialize)]
+struct Compacted {
+    entries_kept: usize,
+    versions_kept: usize,
+    bytes_reclaimed: u64,
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    match run(cli) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("svault: {}", e);
+            if let Some(VaultError::UnsignedHeader { .. }) = VaultError::of(&e) {
+                eprintln!("svault: pass --migrate to upgrade it, if the file has not been out of your hands");
+            }
+            ExitCode::FAILURE
+        }
+    }
+}
+
+fn run(cli: Cli) -> std::io::Result<()> {
+    let json = cli.json;
+    let session = Session {
+        vault: cli.vault,
+        keyfile: cli.keyfile,
+        password_stdin: cli.password_stdin,
+        recover: cli.recover,
+        migrate: cli.migrate,
+    };
+    match cli.command {
+        Command::Init { cipher, kdf_ms, kdf_max_mib } => {
+            session.init(cipher.into(), Duration::from_millis(kdf_ms), kdf_max_mib.saturating_mul(1024))
+        }
+        Command::Add { title, fields, generate } => {
+            let mut vault = session.unlock()?;
+            let mut password = match generate {
+                Some(length) => Zeroizing::new(generate_password(&PasswordRules::new(length))),
+                None => session.read_secret("Entry password: ")?,
+            };
+            // Moved rather than copied, so no unwiped copy is left behind
+            let mut entry = VaultEntry::new(title, String::new(), std::mem::take(&mut *password));
+            fields.apply(&mut entry);
+            vault.write_entry(&entry)?;
+            print_done(json, entry.id, &format!("Added {} ({})", entry.title, entry.id))
+        }
+        Command::Get { entry, field } => {
+            let mut vault = session.unlock()?;
+            let entry = find_entry(&mut vault, &entry)?;
+            match field {
+                Some(name) => {
+                    let value = field_value(&entry, &name)?;
+                    print_json_or(json, &value, || println!("{}", value))
+                }
+                None => print_json_or(json, &entry, || print_entry(&entry)),
+            }
+        }
+        Command::Edit { entry, title, fields, password, generate } => {
+            let mut vault = session.unlock()?;
+            let mut entry = find_entry(&mut vault, &entry)?;
+            if let Some(title) = title {
+                entry.title = title;
+            }
+            fields.apply(&mut entry);
+            if let Some(length) = generate {
+                entry.password = generate_password(&PasswordRules::new(length));
+            } else if password {
+                entry.password = std::mem::take(&mut *session.read_secret("New entry password: ")?);
+            }
+            entry.touch();
+            vault.write_entry(&entry)?;
+            print_done(json, entry.id, &format!("Updated {}", entry.title))
+        }
+        Command::Rm { entry } => {
+            let mut vault = session.unlock()?;
+            let entry = find_entry(&mut vault, &entry)?;
+            vault.delete_entry(entry.id)?;
+            print_done(json, entry.id, &format!("Moved {} to the trash", entry.title))
+        }
+        Command::Ls => {
+            let mut vault = session.unlock()?;
+            let mut entries = vault.list_entries()?;
+            entries.sort_by_cached_key(|(_, title)| title.to_lowercase());
+            print_listed(json, entries.into_iter().map(|(id, title)| Listed { id, title }).collect())
+        }
+        Command::Search { query } => {
+            let mut vault = session.unlock()?;
+            let mut found = Vec::new();
+            for id in vault.search(&query)? {
+                if let Some(entry) = vault.get_entry(id)? {
+                    found.push(Listed { id, title: entry.title });
+                }
+            }
+            print_listed(json, found)
+        }
+        Command::Generate { length, no_symbols, pronounceable } => {
+            let password = if pronounceable {
+                generate_pronounceable_password(length)
+            } else {
+                generate_password(&PasswordRules { use_symbols: !no_symbols, ..PasswordRules::new(length) })
+            };
+            print_json_or(json, &password, || println!("{}", password))
+        }
+        Command::Passwd => {
+            let old_password = session.read_secret("Current master password: ")?;
+            let mut vault = session.unlock_with(&old_password)?;
+            let new_password = session.read_new_password("New master password: ")?;
+            session.change_master_password(&mut vault, &old_password, &new_password)?;
+            if !json {
+                println!("Master password changed");
+            }
+            Ok(())
+        }
+        Command::Compact => {
+            let mut vault = session.unlock()?;
+            let report = vault.compact()?;
+            let compacted = Compacted {
+                entries_kept: report.entries_kept,
+                versions_kept: report.versions_kept,
+                bytes_reclaimed: report.bytes_reclaimed,
+            };
+            print_json_or(json, &compacted, || {
+                println!(
+                    "Kept {} entries ({} versions), reclaimed {} bytes",
+                    report.entries_kept, report.versions_kept, report.bytes_reclaimed
+                )
+            })
+        }
+    }
+}
+
+// The global options every vault command needs
+struct Session {
+    vault: Option<PathBuf>,
+    keyfile: Option<PathBuf>,
+    password_stdin: bool,
+    recover: bool,
+    migrate: bool,
+}
+
+impl Session {
+    fn path(&self) -> std::io::Result<&PathBuf> {
+        self.vault.as_ref().ok_or_else(|| invalid_input("no vault given; pass --vault or set SVAULT_PATH"))
+    }
+
+    fn read_keyfile(&self) -> std::io::Result<Option<Zeroizing<Vec<u8>>>> {
+        match &self.keyfile {
+            Some(p) => Ok(Some(Zeroizing::new(std::fs::read(p)?))),
+            None => Ok(None),
+        }
+    }
+
+    fn read_secret(&self, prompt: &str) -> std::io::Result<Zeroizing<String>> {
+        if !self.password_stdin {
+            return rpassword::prompt_password(prompt).map(Zeroizing::new).map_err(|e| {
+                let message = format!("cannot prompt for a password ({}); pass --password-stdin instead", e);
+                std::io::Error::new(e.kind(), message)
+            });
+        }
+        let mut line = Zeroizing::new(String::new());
+        if std::io::stdin().read_line(&mut line)? == 0 {
+            let message = "stdin ended before every secret was read";
+            return Err(std::io::Error::new(std::io::ErrorKind::UnexpectedEof, message));
+        }
+        let len = line.trim_end_matches(['\n', '\r']).len();
+        line.truncate(len);
+        Ok(line)
+    }
+
+    // Asked twice on a terminal, once from stdin
+    fn read_new_password(&self, prompt: &str) -> std::io::Result<Zeroizing<String>> {
+        let password = self.read_secret(prompt)?;
+        if password.is_empty() {
+            return Err(invalid_input("the master password must not be empty"));
+        }
+        if !self.password_stdin && *self.read_secret("Repeat it: ")? != *password {
+            return Err(invalid_input("the passwords do not match"));
+        }
+        Ok(password)
+    }
+
+    // Calibrated only once the path and password are known to be usable, and
+    // never below the default KDF policy
+    fn init(&self, suite: CipherSuite, kdf_target: Duration, max_mem_kib: u32) -> std::io::Result<()> {
+        let path = self.path()?;
+        if path.exists() {
+            let message = format!("{} already exists", path.display());
+            return Err(std::io::Error::new(std::io::ErrorKind::AlreadyExists, message));
+        }
+        let keyfile = self.read_keyfile()?;
+        let password = self.read_new_password("Master password: ")?;
+        let params = calibrate_argon2(kdf_target, max_mem_kib, &KdfPolicy::default().minimum())
+            .map_err(std::io::Error::other)?;
+        let credential = Credential::password(password.as_bytes(), keyfile.as_deref().map(|k| k.as_slice()));
+        let mut dek = Zeroizing::new([0u8; 32]);
+        rand::thread_rng().fill_bytes(dek.as_mut());
+        let slot = KeySlot::seal(0, credential, params, suite, &dek)?;
+        VaultStore::create(path, VaultHeader::new(vec![slot], keyfile.is_some(), suite), &dek)
+    }
+
+    fn unlock(&self) -> std::io::Result<UnlockedVault> {
+        let password = self.read_secret("Master password: ")?;
+        self.unlock_with(&password)
+    }
+
+    // A password slot below the default KDF policy is re-sealed on the way
+    fn unlock_with(&self, password: &str) -> std::io::Result<UnlockedVault> {
+        let path = self.path()?;
+        let keyfile = self.read_keyfile()?;
+        let credential = Credential::password(password.as_bytes(), keyfile.as_deref().map(|k| k.as_slice()));
+        let options = UnlockOptions {
+            recover: self.recover,
+            migrate_unsigned: self.migrate,
+            kdf_policy: Some(KdfPolicy::default()),
+        };
+        let vault = UnlockedVault::unlock_with_options(path, credential, &options)?;
+        if let Some(torn) = vault.recovered() {
+            eprintln!(
+                "svault: dropped an interrupted write: {} bytes from offset {}, kept {} records",
+                torn.bytes_dropped, torn.offset, torn.records_kept
+            );
+        }
+        if let Some(upgrade) = vault.kdf_upgrade() {
+            let (from, to) = (&upgrade.from, &upgrade.to);
+            eprintln!(
+                "svault: raised the KDF cost of key slot {} from {} KiB x {} to {} KiB x {}",
+                upgrade.slot_id, from.mem_kib, from.iterations, to.mem_kib, to.iterations
+            );
+        }
+        Ok(vault)
+    }
+
+    // Keeps the KDF cost of the slot the old password opens
+    fn change_master_password(
+        &self,
+        vault: &mut UnlockedVault,
+        old_password: &str,
+        new_password: &str,
+    ) -> std::io::Result<()> {
+        let keyfile = self.read_keyfile()?;
+        let keyfile = keyfile.as_deref().map(|k| k.as_slice());
+        vault.change_master_password(
+            Credential::password(old_password.as_bytes(), keyfile),
+            Credential::password(new_password.as_bytes(), keyfile),
+            None,
+        )
+    }
+}
+
+// `query` is an entry id, or a title matched without regard to case
+fn find_entry(vault: &mut UnlockedVault, query: &str) -> std::io::Result<VaultEntry> {
+    if let Ok(id) = Uuid::parse_str(query) {
+        if let Some(entry) = vault.get_entry(id)? {
+            return Ok(entry);
+        }
+    }
+    let wanted = query.to_lowercase();
+    let ids: Vec<Uuid> = vault
+        .list_entries()?
+        .into_iter()
+        .filter(|(_, title)| title.to_lowercase() == wanted)
+        .map(|(id, _)| id)
+        .collect();
+    match ids[..] {
+        [id] => vault.get_entry(id)?.ok_or_else(|| entry_not_found(query)),
+        [] => Err(entry_not_found(query)),
+        _ => Err(invalid_input(&format!("{} entries are titled {}; give an id instead", ids.len(), query))),
+    }
+}
+
+fn field_value(entry: &VaultEntry, name: &str) -> std::io::Result<String> {
+    let value = match name {
+        "title" => Some(entry.title.clone()),
+        "username" => Some(entry.username.clone()),
+        "password" => Some(entry.password.clone()),
+        "url" => entry.urls.first().cloned(),
+        "notes" => Some(entry.notes.clone()),
+        _ => entry.custom_fields.iter().find(|f| f.name == name).map(|f| f.value.clone()),
+    };
+    let message = format!("{} has no field {}", entry.title, name);
+    value.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, message))
+}
+
+fn print_entry(entry: &VaultEntry) {
+    println!("id:       {}", entry.id);
+    println!("title:    {}", entry.title);
+    println!("username: {}", entry.username);
+    println!("password: {}", entry.password);
+    for url in &entry.urls {
+        println!("url:      {}", url);
+    }
+    if !entry.tags.is_empty() {
+        println!("tags:     {}", entry.tags.join(", "));
+    }
+    for field in &entry.custom_fields {
+        println!("{}: {}", field.name, field.value);
+    }
+    if !entry.notes.is_empty() {
+        println!("notes:\n{}", entry.notes);
+    }
+}
+
+fn print_listed(json: bool, entries: Vec<Listed>) -> std::io::Result<()> {
+    print_json_or(json, &entries, || {
+        for e in &entries {
+            println!("{}  {}", e.id, e.title);
+        }
+    })
+}
+
+// Commands that change one entry report its id
+fn print_done(json: bool, id: Uuid, message: &str) -> std::io::Result<()> {
+    print_json_or(json, &serde_json::json!({ "id": id }), || println!("{}", message))
+}
+
+fn print_json_or<T: Serialize>(json: bool, value: &T, text: impl FnOnce()) -> std::io::Result<()> {
+    if json {
+        println!("{}", serde_json::to_string_pretty(value).map_err(std::io::Error::other)?);
+    } else {
+        text();
+    }
+    Ok(())
+}
+
+fn entry_not_found(query: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::NotFound, format!("no entry {}", query))
+}
+
+fn invalid_input(message: &str) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, message.to_string())
+}
@@ -0,0 +1,66 @@
+// Drives the svault binary the way a script would: secrets on stdin, JSON out
+
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+fn svault(vault: &PathBuf, stdin: &str, args: &[&str]) -> Result<String, String> {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_svault"))
+        .arg("--vault")
+        .arg(vault)
+        .arg("--password-stdin")
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    // A command refused up front exits without reading stdin
+    let _ = child.stdin.take().unwrap().write_all(stdin.as_bytes());
+    let out = child.wait_with_output().unwrap();
+    if out.status.success() {
+        Ok(String::from_utf8(out.stdout).unwrap())
+    } else {
+        Err(String::from_utf8(out.stderr).unwrap())
+    }
+}
+
+fn json(out: &str) -> serde_json::Value {
+    serde_json::from_str(out).unwrap()
+}
+
+#[test]
+fn scripted_session() {
+    let vault = std::env::temp_dir().join(format!("svault-cli-{}.svlt", std::process::id()));
+    svault(&vault, "pw\n", &["init", "--kdf-ms", "10", "--kdf-max-mib", "32"]).unwrap();
+    assert!(svault(&vault, "pw\n", &["init"]).is_err(), "init must not overwrite a vault");
+
+    let added = svault(&vault, "pw\nhunter2\n", &["--json", "add", "Email", "-u", "alice", "--tag", "mail"]).unwrap();
+    let id = json(&added)["id"].as_str().unwrap().to_string();
+    svault(&vault, "pw\n", &["add", "Bank", "--generate", "24"]).unwrap();
+
+    assert_eq!(svault(&vault, "pw\n", &["get", "email", "--field", "password"]).unwrap(), "hunter2\n");
+    let entry = json(&svault(&vault, "pw\n", &["get", &id, "--json"]).unwrap());
+    assert_eq!((entry["title"].as_str(), entry["username"].as_str()), (Some("Email"), Some("alice")));
+    let bank = svault(&vault, "pw\n", &["get", "Bank", "--field", "password"]).unwrap();
+    assert_eq!(bank.trim_end().chars().count(), 24);
+
+    svault(&vault, "pw\nhunter3\n", &["edit", "Email", "--password", "--notes", "edited"]).unwrap();
+    let entry = json(&svault(&vault, "pw\n", &["--json", "get", "Email"]).unwrap());
+    assert_eq!((entry["password"].as_str(), entry["notes"].as_str()), (Some("hunter3"), Some("edited")));
+
+    svault(&vault, "pw\n", &["rm", "Bank"]).unwrap();
+    let listed = json(&svault(&vault, "pw\n", &["ls", "--json"]).unwrap());
+    assert_eq!(listed.as_array().unwrap().len(), 1);
+    assert_eq!(listed[0]["id"].as_str(), Some(id.as_str()));
+
+    svault(&vault, "pw\nnew pw\n", &["passwd"]).unwrap();
+    assert!(svault(&vault, "pw\n", &["ls"]).unwrap_err().contains("wrong password"));
+    let found = json(&svault(&vault, "new pw\n", &["--json", "search", "mail"]).unwrap());
+    assert_eq!(found[0]["title"].as_str(), Some("Email"));
+
+    let _ = std::fs::remove_file(&vault);
+    let mut lock = vault.into_os_string();
+    lock.push(".lock");
+    let _ = std::fs::remove_file(lock);
+}
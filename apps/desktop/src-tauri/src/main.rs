@@ -1,9 +1,15 @@
-use core_crypto::{derive_kek, unwrap_key_aes_gcm, wrap_key_aes_gcm, generate_password, generate_pronounceable_password, ArgonParams, PasswordRules};
+use core_crypto::{calibrate_argon2, generate_password, generate_pronounceable_password, CipherSuite, PasswordRules};
 use platform::{ClipboardManager, IdleDetector};
 use rand::RngCore;
+use std::collections::HashMap;
 use std::sync::{Arc, Mutex};
-use tauri::State;
-use vault_store::{ArgonParamsOnDisk, VaultEntry, VaultHeader, VaultStore};
+use std::time::Duration;
+use tauri::{AppHandle, Emitter, State};
+use vault_store::{
+    Attachment, Credential, CustomField, KdfPolicy, KeySlot, SlotType, TrashedEntry, UnlockOptions, UnlockedVault,
+    VaultEntry, VaultError, VaultHeader, VaultStore,
+};
+use zeroize::Zeroizing;
 use uuid::Uuid;
 
 // Global state for auto-lock and clipboard management
@@ -11,142 +17,398 @@ struct AppState {
     idle_detector: Arc<Mutex<Option<IdleDetector>>>,
     clipboard_manager: Arc<Mutex<Option<ClipboardManager>>>,
     is_locked: Arc<Mutex<bool>>,
+    // Unlocked vaults keyed by the session handle returned from `unlock_vault`
+    sessions: Arc<Mutex<HashMap<String, UnlockedVault>>>,
+}
+
+impl AppState {
+    fn with_session<T>(
+        &self,
+        session: &str,
+        f: impl FnOnce(&mut UnlockedVault) -> std::io::Result<T>,
+    ) -> Result<T, String> {
+        let mut sessions = self.sessions.lock().map_err(|e| e.to_string())?;
+        let vault = sessions.get_mut(session).ok_or_else(|| "Vault is locked".to_string())?;
+        f(vault).map_err(|e| e.to_string())
+    }
+
+    fn start_session(&self, vault: UnlockedVault) -> Result<String, String> {
+        let session = Uuid::new_v4().to_string();
+
+        // Set up auto-lock and clipboard management
+        let idle_detector = IdleDetector::new(300); // 5 minutes
+        let clipboard_manager = ClipboardManager::new(30); // 30 seconds
+
+        // Update state first
+        if let Ok(mut detector) = self.idle_detector.lock() {
+            *detector = Some(idle_detector);
+        }
+        if let Ok(mut manager) = self.clipboard_manager.lock() {
+            *manager = Some(clipboard_manager);
+        }
+
+        // Auto-lock monitoring will be handled by the frontend
+        // The idle detector is set up and ready to be used
+
+        self
+            .sessions
+            .lock()
+            .map_err(|e| e.to_string())?
+            .insert(session.clone(), vault);
+        if let Ok(mut locked) = self.is_locked.lock() {
+            *locked = false;
+        }
+
+        Ok(session)
+    }
+
+    // Dropping the sessions zeroizes their DEKs
+    fn lock_all(&self) {
+        if let Ok(mut sessions) = self.sessions.lock() {
+            sessions.clear();
+        }
+        if let Ok(mut locked) = self.is_locked.lock() {
+            *locked = true;
+        }
+    }
 }
 
 #[tauri::command]
 fn ping() -> String { "pong".into() }
 
+// Keyfiles are read whole; they are small random blobs made by `generate_keyfile`
+fn read_keyfile(keyfile_path: Option<String>) -> Result<Option<Zeroizing<Vec<u8>>>, String> {
+    match keyfile_path {
+        Some(p) => Ok(Some(Zeroizing::new(std::fs::read(p).map_err(|e| e.to_string())?))),
+        None => Ok(None),
+    }
+}
+
+// How long unlocking a new vault should take on this machine, and the most
+// memory its KDF may use
+const DEFAULT_KDF_TARGET: Duration = Duration::from_secs(1);
+const MAX_KDF_MEM_KIB: u32 = 1024 * 1024;
+
+// The KDF is calibrated to take `kdf_target_ms` (default one second) here,
+// but never below the default KDF policy, which unlocking would otherwise
+// raise it to on first use. `cipher_suite` defaults to AES-256-GCM.
+#[tauri::command]
+fn create_vault(
+	path: String,
+	master_password: String,
+	keyfile_path: Option<String>,
+	cipher_suite: Option<CipherSuite>,
+	kdf_target_ms: Option<u64>,
+) -> Result<(), String> {
+	let suite = cipher_suite.unwrap_or_default();
+	let target = kdf_target_ms.map(Duration::from_millis).unwrap_or(DEFAULT_KDF_TARGET);
+	let params = calibrate_argon2(target, MAX_KDF_MEM_KIB, &KdfPolicy::default().minimum()).map_err(|e| e.to_string())?;
+	let keyfile = read_keyfile(keyfile_path)?;
+	let credential = Credential::password(master_password.as_bytes(), keyfile.as_deref().map(|k| k.as_slice()));
+	let mut dek = Zeroizing::new([0u8; 32]);
+	rand::thread_rng().fill_bytes(dek.as_mut());
+	let slot = KeySlot::seal(0, credential, params, suite, &dek).map_err(|e| e.to_string())?;
+	let header = VaultHeader::new(vec![slot], keyfile.is_some(), suite);
+	VaultStore::create(path, header, &dek).map_err(|e| e.to_string())
+}
+
+// Writes a new random keyfile; refuses to overwrite an existing file
 #[tauri::command]
-fn create_vault(path: String, master_password: String) -> Result<(), String> {
-	let params = ArgonParams { mem_kib: 256 * 1024, iterations: 3, parallelism: 4 };
-	let mut salt = [0u8; 32];
-	rand::thread_rng().fill_bytes(&mut salt);
-	let kek = derive_kek(master_password.as_bytes(), &params, &salt);
-	let mut dek = [0u8; 32];
-	rand::thread_rng().fill_bytes(&mut dek);
-	let mut nonce = [0u8; 12];
-	rand::thread_rng().fill_bytes(&mut nonce);
-	let wrapped = wrap_key_aes_gcm(&kek, &dek, &nonce);
-	let header = VaultHeader {
-		magic: *b"SVLT1",
-		version: 1,
-		kdf_params: ArgonParamsOnDisk { mem_kib: params.mem_kib, iterations: params.iterations, parallelism: params.parallelism },
-		salt_kek: salt,
-		wrapped_dek: wrapped,
-	};
-	VaultStore::create(path, header).map_err(|e| e.to_string())
+fn generate_keyfile(path: String) -> Result<(), String> {
+    use std::io::Write;
+    let keyfile = Zeroizing::new(core_crypto::generate_keyfile());
+    let mut f = std::fs::OpenOptions::new()
+        .create_new(true)
+        .write(true)
+        .open(&path)
+        .map_err(|e| e.to_string())?;
+    f.write_all(&keyfile).map_err(|e| e.to_string())?;
+    f.sync_all().map_err(|e| e.to_string())
 }
 
+// Prefix of the unlock error for an interrupted write, followed by the bytes
+// recovery would drop and the records it keeps, so the UI can say what is lost
+// before offering to retry with `recover` set
+const TORN_WRITE_ERROR: &str = "TORN_WRITE";
+
+// Prefix of the unlock error for a vault from before headers were signed, so
+// the UI can ask before retrying with `migrate` set
+const UNSIGNED_HEADER_ERROR: &str = "UNSIGNED_HEADER";
+
+// Derives the KEK once and keeps the DEK in `AppState`. Returns the session
+// handle that the entry commands take instead of the master password. A
+// password slot below the default KDF policy is re-sealed on the way, which
+// emits `kdf-upgraded` with the slot's old and new costs.
 #[tauri::command]
 fn unlock_vault(
     path: String, 
     master_password: String, 
+    keyfile_path: Option<String>,
+    recover: Option<bool>,
+    migrate: Option<bool>,
+    app: AppHandle,
     state: State<AppState>
-) -> Result<bool, String> {
-    let vs = VaultStore::open(path).map_err(|e| e.to_string())?;
-    let params = ArgonParams::from(vs.header.kdf_params.clone());
-    let kek = derive_kek(master_password.as_bytes(), &params, &vs.header.salt_kek);
-    let _dek = unwrap_key_aes_gcm(&kek, &vs.header.wrapped_dek).map_err(|e| e)?;
-    
-    // Set up auto-lock and clipboard management
-    let idle_detector = IdleDetector::new(300); // 5 minutes
-    let clipboard_manager = ClipboardManager::new(30); // 30 seconds
-    
-    // Update state first
-    if let Ok(mut detector) = state.idle_detector.lock() {
-        *detector = Some(idle_detector);
-    }
-    if let Ok(mut manager) = state.clipboard_manager.lock() {
-        *manager = Some(clipboard_manager);
+) -> Result<String, String> {
+    let keyfile = read_keyfile(keyfile_path)?;
+    let credential = Credential::password(master_password.as_bytes(), keyfile.as_deref().map(|k| k.as_slice()));
+    let options = UnlockOptions {
+        recover: recover.unwrap_or(false),
+        migrate_unsigned: migrate.unwrap_or(false),
+        kdf_policy: Some(KdfPolicy::default()),
+    };
+    let vault = UnlockedVault::unlock_with_options(&path, credential, &options).map_err(|e| match VaultError::of(&e) {
+        Some(VaultError::TornWrite(torn)) => {
+            format!("{} {} {}: {}", TORN_WRITE_ERROR, torn.bytes_dropped, torn.records_kept, e)
+        }
+        Some(VaultError::UnsignedHeader { .. }) => format!("{}: {}", UNSIGNED_HEADER_ERROR, e),
+        _ => e.to_string(),
+    })?;
+    if let Some(upgrade) = vault.kdf_upgrade() {
+        let _ = app.emit("kdf-upgraded", upgrade);
     }
-    
-    // Auto-lock monitoring will be handled by the frontend
-    // The idle detector is set up and ready to be used
-    
-    if let Ok(mut locked) = state.is_locked.lock() {
-        *locked = false;
+    state.start_session(vault)
+}
+
+// Same as `unlock_vault`, using the printed recovery key instead of the master password
+#[tauri::command]
+fn unlock_vault_with_recovery_key(
+    path: String,
+    recovery_key: String,
+    state: State<AppState>
+) -> Result<String, String> {
+    let vault = UnlockedVault::unlock_with_recovery_key(&path, &recovery_key).map_err(|e| e.to_string())?;
+    state.start_session(vault)
+}
+
+#[tauri::command]
+fn lock_vault(session: String, state: State<AppState>) -> Result<(), String> {
+    let mut sessions = state.sessions.lock().map_err(|e| e.to_string())?;
+    sessions.remove(&session);
+    if sessions.is_empty() {
+        if let Ok(mut locked) = state.is_locked.lock() {
+            *locked = true;
+        }
     }
-    
-    Ok(true)
+    Ok(())
 }
 
 #[tauri::command]
-fn create_entry(path: String, master_password: String, title: String, username: String, password: String) -> Result<String, String> {
-	let vs = VaultStore::open(&path).map_err(|e| e.to_string())?;
-	let params = ArgonParams::from(vs.header.kdf_params.clone());
-	let kek = derive_kek(master_password.as_bytes(), &params, &vs.header.salt_kek);
-	let dek = unwrap_key_aes_gcm(&kek, &vs.header.wrapped_dek).map_err(|e| e.to_string())?;
-	
-	let entry = VaultEntry {
-		id: Uuid::new_v4(),
-		title,
-		username,
-		password,
-	};
-	vs.write_entry(&dek, &entry).map_err(|e| e.to_string())?;
+#[allow(clippy::too_many_arguments)]
+fn create_entry(
+	session: String,
+	title: String,
+	username: String,
+	password: String,
+	urls: Option<Vec<String>>,
+	notes: Option<String>,
+	custom_fields: Option<Vec<CustomField>>,
+	tags: Option<Vec<String>>,
+	state: State<AppState>
+) -> Result<String, String> {
+	let mut entry = VaultEntry::new(title, username, password);
+	entry.urls = urls.unwrap_or_default();
+	entry.notes = notes.unwrap_or_default();
+	entry.custom_fields = custom_fields.unwrap_or_default();
+	entry.tags = tags.unwrap_or_default();
+	state.with_session(&session, |vault| vault.write_entry(&entry))?;
 	Ok(entry.id.to_string())
 }
 
 #[tauri::command]
-fn list_entries(path: String, master_password: String) -> Result<Vec<(String, String)>, String> {
-	let vs = VaultStore::open(&path).map_err(|e| e.to_string())?;
-	let params = ArgonParams::from(vs.header.kdf_params.clone());
-	let kek = derive_kek(master_password.as_bytes(), &params, &vs.header.salt_kek);
-	let dek = unwrap_key_aes_gcm(&kek, &vs.header.wrapped_dek).map_err(|e| e.to_string())?;
-	
-	let entries = vs.list_entries(&dek).map_err(|e| e.to_string())?;
+fn list_entries(session: String, state: State<AppState>) -> Result<Vec<(String, String)>, String> {
+	let entries = state.with_session(&session, |vault| vault.list_entries())?;
 	Ok(entries.into_iter().map(|(id, title)| (id.to_string(), title)).collect())
 }
 
+// Ranked IDs of matching entries. Usernames, URLs and notes are searched here,
+// so only titles reach the webview, through `list_entries`.
 #[tauri::command]
-fn read_entry(path: String, master_password: String, entry_id: String) -> Result<VaultEntry, String> {
-	let vs = VaultStore::open(&path).map_err(|e| e.to_string())?;
-	let params = ArgonParams::from(vs.header.kdf_params.clone());
-	let kek = derive_kek(master_password.as_bytes(), &params, &vs.header.salt_kek);
-	let dek = unwrap_key_aes_gcm(&kek, &vs.header.wrapped_dek).map_err(|e| e.to_string())?;
-	
+fn search_entries(session: String, query: String, state: State<AppState>) -> Result<Vec<String>, String> {
+	let ids = state.with_session(&session, |vault| vault.search(&query))?;
+	Ok(ids.into_iter().map(|id| id.to_string()).collect())
+}
+
+#[tauri::command]
+fn read_entry(session: String, entry_id: String, state: State<AppState>) -> Result<VaultEntry, String> {
 	let id = Uuid::parse_str(&entry_id).map_err(|e| e.to_string())?;
-	vs.get_entry(&dek, id).map_err(|e| e.to_string())?.ok_or_else(|| "Entry not found".to_string())
+	state.with_session(&session, |vault| vault.get_entry(id))?.ok_or_else(|| "Entry not found".to_string())
 }
 
+// Every saved version of an entry, oldest first
 #[tauri::command]
+fn entry_history(session: String, entry_id: String, state: State<AppState>) -> Result<Vec<VaultEntry>, String> {
+	let id = Uuid::parse_str(&entry_id).map_err(|e| e.to_string())?;
+	state.with_session(&session, |vault| vault.entry_history(id))
+}
+
+// Save version `version` of `entry_history` again as the current one
+#[tauri::command]
+fn restore_entry_version(session: String, entry_id: String, version: usize, state: State<AppState>) -> Result<VaultEntry, String> {
+	let id = Uuid::parse_str(&entry_id).map_err(|e| e.to_string())?;
+	state.with_session(&session, |vault| vault.restore_version(id, version))
+}
+
+// Fields left as None keep their stored value
+#[tauri::command]
+#[allow(clippy::too_many_arguments)]
 fn update_entry(
-	path: String, 
-	master_password: String, 
+	session: String, 
 	id: String, 
 	title: String, 
 	username: String, 
-	password: String
+	password: String,
+	urls: Option<Vec<String>>,
+	notes: Option<String>,
+	custom_fields: Option<Vec<CustomField>>,
+	tags: Option<Vec<String>>,
+	state: State<AppState>
 ) -> Result<bool, String> {
-	let vs = VaultStore::open(&path).map_err(|e| e.to_string())?;
-	let params = ArgonParams::from(vs.header.kdf_params.clone());
-	let kek = derive_kek(master_password.as_bytes(), &params, &vs.header.salt_kek);
-	let dek = unwrap_key_aes_gcm(&kek, &vs.header.wrapped_dek).map_err(|e| e.to_string())?;
 	let id_uuid = Uuid::parse_str(&id).map_err(|e| e.to_string())?;
 	
-	let entry = VaultEntry {
-		id: id_uuid,
-		title,
-		username,
-		password,
-	};
-	
-	vs.update_entry(&dek, entry).map_err(|e| e.to_string())?;
+	state.with_session(&session, |vault| {
+		let mut entry = vault
+			.get_entry(id_uuid)?
+			.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "Entry not found"))?;
+		entry.title = title;
+		entry.username = username;
+		entry.password = password;
+		if let Some(urls) = urls { entry.urls = urls; }
+		if let Some(notes) = notes { entry.notes = notes; }
+		if let Some(custom_fields) = custom_fields { entry.custom_fields = custom_fields; }
+		if let Some(tags) = tags { entry.tags = tags; }
+		entry.touch();
+		vault.update_entry(entry)
+	})?;
 	Ok(true)
 }
 
 #[tauri::command]
-fn delete_entry(path: String, master_password: String, id: String) -> Result<bool, String> {
-	let vs = VaultStore::open(&path).map_err(|e| e.to_string())?;
-	let params = ArgonParams::from(vs.header.kdf_params.clone());
-	let kek = derive_kek(master_password.as_bytes(), &params, &vs.header.salt_kek);
-	let dek = unwrap_key_aes_gcm(&kek, &vs.header.wrapped_dek).map_err(|e| e.to_string())?;
+fn delete_entry(session: String, id: String, state: State<AppState>) -> Result<bool, String> {
 	let id_uuid = Uuid::parse_str(&id).map_err(|e| e.to_string())?;
 	
-	vs.delete_entry(&dek, id_uuid).map_err(|e| e.to_string())?;
+	state.with_session(&session, |vault| vault.delete_entry(id_uuid))?;
 	Ok(true)
 }
 
+// The file is streamed into the vault under its own file name
+#[tauri::command]
+fn add_attachment(session: String, entry_id: String, path: String, state: State<AppState>) -> Result<Attachment, String> {
+	let id = Uuid::parse_str(&entry_id).map_err(|e| e.to_string())?;
+	let path = std::path::Path::new(&path);
+	let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+	let file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+	state.with_session(&session, |vault| vault.add_attachment(id, &name, file))
+}
+
+#[tauri::command]
+fn list_attachments(session: String, entry_id: String, state: State<AppState>) -> Result<Vec<Attachment>, String> {
+	let id = Uuid::parse_str(&entry_id).map_err(|e| e.to_string())?;
+	state.with_session(&session, |vault| vault.list_attachments(id))
+}
+
+// Decrypts the attachment to `dest_path`, which must not exist yet
+#[tauri::command]
+fn extract_attachment(session: String, attachment_id: String, dest_path: String, state: State<AppState>) -> Result<u64, String> {
+	let id = Uuid::parse_str(&attachment_id).map_err(|e| e.to_string())?;
+	let file = std::fs::OpenOptions::new().write(true).create_new(true).open(&dest_path).map_err(|e| e.to_string())?;
+	let written = state.with_session(&session, |vault| vault.extract_attachment(id, std::io::BufWriter::new(file)));
+	if written.is_err() {
+		let _ = std::fs::remove_file(&dest_path);
+	}
+	written
+}
+
+#[tauri::command]
+fn remove_attachment(session: String, attachment_id: String, state: State<AppState>) -> Result<(), String> {
+	let id = Uuid::parse_str(&attachment_id).map_err(|e| e.to_string())?;
+	state.with_session(&session, |vault| vault.remove_attachment(id))
+}
+
+#[tauri::command]
+fn list_trash(session: String, state: State<AppState>) -> Result<Vec<TrashedEntry>, String> {
+	state.with_session(&session, |vault| vault.list_trash())
+}
+
+#[tauri::command]
+fn restore_from_trash(session: String, entry_id: String, state: State<AppState>) -> Result<VaultEntry, String> {
+	let id = Uuid::parse_str(&entry_id).map_err(|e| e.to_string())?;
+	state.with_session(&session, |vault| vault.restore_from_trash(id))
+}
+
+// Returns the number of bytes reclaimed
+#[tauri::command]
+fn empty_trash(session: String, state: State<AppState>) -> Result<u64, String> {
+	let report = state.with_session(&session, |vault| vault.empty_trash())?;
+	Ok(report.bytes_reclaimed)
+}
+
+// Keeps the KDF cost of the slot the old password opens; only the salt and
+// wrapped DEK change
+#[tauri::command]
+fn change_master_password(
+    session: String,
+    old_password: String,
+    new_password: String,
+    keyfile_path: Option<String>,
+    state: State<AppState>,
+) -> Result<(), String> {
+    let keyfile = read_keyfile(keyfile_path)?;
+    let keyfile = keyfile.as_deref().map(|k| k.as_slice());
+    state.with_session(&session, |vault| {
+        vault.change_master_password(
+            Credential::password(old_password.as_bytes(), keyfile),
+            Credential::password(new_password.as_bytes(), keyfile),
+            None,
+        )
+    })
+}
+
+// Re-encrypts every live entry under a new DEK. Key slots other than the
+// master password are dropped and must be enrolled again. Emits `rotate-dek-progress`
+// events carrying (done, total) so the UI can show progress on large vaults.
+#[tauri::command]
+fn rotate_dek(
+    session: String,
+    master_password: String,
+    keyfile_path: Option<String>,
+    app: AppHandle,
+    state: State<AppState>,
+) -> Result<(), String> {
+    let keyfile = read_keyfile(keyfile_path)?;
+    let credential = Credential::password(master_password.as_bytes(), keyfile.as_deref().map(|k| k.as_slice()));
+    state.with_session(&session, |vault| {
+        vault.rotate_dek(credential, |done, total| {
+            let _ = app.emit("rotate-dek-progress", (done, total));
+        })
+    })
+}
+
+// Replaces any earlier recovery key. The returned key is shown once and never stored.
+#[tauri::command]
+fn generate_recovery_key(session: String, state: State<AppState>) -> Result<String, String> {
+    state.with_session(&session, |vault| vault.generate_recovery_key())
+}
+
+// Returns (slot id, slot type) for every key slot in the header
+#[tauri::command]
+fn list_key_slots(session: String, state: State<AppState>) -> Result<Vec<(u32, SlotType)>, String> {
+    state.with_session(&session, |vault| {
+        Ok(vault.store().list_key_slots().iter().map(|s| (s.id, s.slot_type)).collect())
+    })
+}
+
+#[tauri::command]
+fn remove_key_slot(session: String, slot_id: u32, state: State<AppState>) -> Result<(), String> {
+    state.with_session(&session, |vault| vault.remove_key_slot(slot_id))
+}
+
+// Returns the number of bytes reclaimed
+#[tauri::command]
+fn compact_vault(session: String, state: State<AppState>) -> Result<u64, String> {
+	let report = state.with_session(&session, |vault| vault.compact())?;
+	Ok(report.bytes_reclaimed)
+}
+
 #[tauri::command]
 fn copy_to_clipboard(text: String, state: State<'_, AppState>) -> Result<(), String> {
     if let Ok(manager) = state.clipboard_manager.lock() {
@@ -196,9 +458,7 @@ fn check_auto_lock(state: State<AppState>) -> Result<bool, String> {
             
             // Check if 5 minutes (300 seconds) have passed
             if now - last >= 300 {
-                if let Ok(mut locked) = state.is_locked.lock() {
-                    *locked = true;
-                }
+                state.lock_all();
                 return Ok(true);
             }
         }
@@ -249,6 +509,7 @@ fn main() {
         idle_detector: Arc::new(Mutex::new(None)),
         clipboard_manager: Arc::new(Mutex::new(None)),
         is_locked: Arc::new(Mutex::new(true)),
+        sessions: Arc::new(Mutex::new(HashMap::new())),
     };
 
     tauri::Builder::default()
@@ -256,12 +517,31 @@ fn main() {
         .invoke_handler(tauri::generate_handler![
             ping, 
             create_vault, 
+            generate_keyfile,
             unlock_vault, 
+            unlock_vault_with_recovery_key,
+            lock_vault,
             create_entry, 
             list_entries, 
+            search_entries,
             read_entry,
+            entry_history,
+            restore_entry_version,
             update_entry,
             delete_entry,
+            add_attachment,
+            list_attachments,
+            extract_attachment,
+            remove_attachment,
+            list_trash,
+            restore_from_trash,
+            empty_trash,
+            compact_vault,
+            change_master_password,
+            rotate_dek,
+            generate_recovery_key,
+            list_key_slots,
+            remove_key_slot,
             copy_to_clipboard,
             record_activity,
             is_vault_locked,
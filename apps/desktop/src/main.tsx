@@ -25,11 +25,23 @@ async function invoke<T>(cmd: string, args?: Record<string, unknown>): Promise<T
 	return invoke<T>(cmd, args)
 }
 
+interface CustomField {
+	name: string
+	value: string
+	concealed: boolean
+}
+
 interface Entry {
 	id: string
 	title: string
 	username: string
 	password: string
+	urls: string[]
+	notes: string
+	custom_fields: CustomField[]
+	tags: string[]
+	created_at: number
+	modified_at: number
 }
 
 function App() {
@@ -40,12 +52,15 @@ function App() {
   );
   const [master, setMaster] = useState("");
   const [unlocked, setUnlocked] = useState(false);
+  const [session, setSession] = useState<string | null>(null);
   const [entries, setEntries] = useState<Array<[string, string]>>([]);
   const [newEntry, setNewEntry] = useState({ title: "", username: "", password: "" });
   const [showPassword, setShowPassword] = useState(false);
   const [editingEntry, setEditingEntry] = useState<Entry | null>(null);
   const [showDeleteConfirm, setShowDeleteConfirm] = useState<{ id: string; title: string } | null>(null);
   const [searchTerm, setSearchTerm] = useState("");
+  // Ranked entry IDs from the backend search, or null when not searching
+  const [searchResults, setSearchResults] = useState<string[] | null>(null);
   
   // Loading states
   const [isLoading, setIsLoading] = useState(false);
@@ -70,6 +85,7 @@ function App() {
           const shouldLock = await invoke<boolean>("check_auto_lock");
           if (shouldLock) {
             setUnlocked(false);
+            setSession(null);
             toast({ title: "Vault locked", description: "Auto-locked due to inactivity." });
           }
         } catch {}
@@ -78,6 +94,19 @@ function App() {
     }
   }, [unlocked]);
 
+  // --- Search runs in the backend over the unlocked vault's index ---
+  useEffect(() => {
+    if (!session || !searchTerm.trim()) {
+      setSearchResults(null);
+      return;
+    }
+    let cancelled = false;
+    invoke<string[]>("search_entries", { session, query: searchTerm })
+      .then((ids) => { if (!cancelled) setSearchResults(ids); })
+      .catch(() => { if (!cancelled) setSearchResults([]); });
+    return () => { cancelled = true; };
+  }, [searchTerm, session, entries]);
+
   const onCreate = async () => {
     setIsCreating(true);
     try {
@@ -90,31 +119,47 @@ function App() {
     }
   };
 
-  const onUnlock = async () => {
+  const onUnlock = async (recover = false, migrate = false) => {
     setIsUnlocking(true);
     try {
-      const ok = await invoke<boolean>("unlock_vault", { path, masterPassword: master });
-      if (ok) {
-        setUnlocked(true);
-        // Keep master password in memory for session operations
-        await loadEntries();
-        toast({ title: "Vault opened", description: path.split("/").pop() });
-      } else {
-        toast({ title: "Failed", description: "Incorrect master password.", variant: "destructive" });
-      }
+      const handle = await invoke<string>("unlock_vault", { path, masterPassword: master, recover, migrate });
+      setSession(handle);
+      setUnlocked(true);
+      // The backend holds the unlocked vault; the master password is no longer needed
+      setMaster("");
+      await loadEntries(handle);
+      toast({ title: "Vault opened", description: path.split("/").pop() });
     } catch (e: any) {
+      // A crash interrupted the last save; recovering discards that write.
+      // Say how much goes: a crash loses one save, a large cut means more.
+      const torn = /^TORN_WRITE (\d+) (\d+):/.exec(String(e));
+      if (!recover && torn &&
+          window.confirm(`The last change to this vault was interrupted and is incomplete. ` +
+            `Recovering discards the last ${torn[1]} bytes of the file and keeps the ${torn[2]} records before them. ` +
+            `Recover and open the vault?`)) {
+        setIsUnlocking(false);
+        return onUnlock(true, migrate);
+      }
+      // Vaults from before header signing are upgraded only once the user
+      // vouches for the file, since upgrading signs its header as it is
+      if (!migrate && String(e).startsWith("UNSIGNED_HEADER") &&
+          window.confirm("This vault was made by an old version that cannot show whether the file was changed. " +
+            "Upgrading it accepts the file as it is now. Only continue if it has not been out of your hands. " +
+            "Upgrade and open the vault?")) {
+        setIsUnlocking(false);
+        return onUnlock(recover, true);
+      }
       toast({ title: "Error", description: String(e), variant: "destructive" });
     } finally {
       setIsUnlocking(false);
     }
   };
 
-  const loadEntries = async () => {
+  const loadEntries = async (handle: string | null = session) => {
     setIsLoading(true);
     try {
       const list = await invoke<Array<[string, string]>>("list_entries", {
-        path,
-        masterPassword: master,
+        session: handle,
       });
       setEntries(list);
     } catch (e: any) {
@@ -128,8 +173,7 @@ function App() {
     setIsCreatingEntry(true);
     try {
       await invoke<string>("create_entry", {
-        path,
-        masterPassword: master,
+        session,
         title: newEntry.title,
         username: newEntry.username,
         password: newEntry.password,
@@ -148,8 +192,7 @@ function App() {
   const copyPassword = async (id: string) => {
     try {
       const entry = await invoke<Entry>("read_entry", { 
-        path, 
-        masterPassword: master,   // ← required
+        session,
         entryId: id 
       });
       await invoke<void>("copy_to_clipboard", { text: entry.password });
@@ -163,8 +206,7 @@ function App() {
     setIsEditing(true);
     try {
       const entry = await invoke<Entry>("read_entry", { 
-        path, 
-        masterPassword: master,   // ← required
+        session,
         entryId: id 
       });
       setEditingEntry(entry);
@@ -180,8 +222,7 @@ function App() {
     setIsEditing(true);
     try {
       await invoke<boolean>("update_entry", {
-        path,
-        masterPassword: master,   // ← required
+        session,
         ...editingEntry,
       });
       setEditingEntry(null);
@@ -198,13 +239,12 @@ function App() {
     setIsDeleting(true);
     try {
       await invoke<boolean>("delete_entry", { 
-        path, 
-        masterPassword: master,   // ← required
+        session,
         id 
       });
       setShowDeleteConfirm(null);
       await loadEntries();
-      toast({ title: "Entry deleted", description: "Entry removed successfully." });
+      toast({ title: "Entry moved to trash", description: "You can restore it until the trash is emptied." });
     } catch (e: any) {
       toast({ title: "Error", description: String(e), variant: "destructive" });
     } finally {
@@ -212,7 +252,13 @@ function App() {
     }
   };
 
-  const handleQuitVault = () => {
+  const handleQuitVault = async () => {
+    if (session) {
+      try {
+        await invoke<void>("lock_vault", { session });
+      } catch {}
+    }
+    setSession(null);
     setUnlocked(false);
     setMaster(""); // Clear master password
     setEntries([]);
@@ -262,9 +308,13 @@ function App() {
   };
 
   // --- Filtered and sorted entries ---
-  const filteredEntries = entries
-    .filter(([_, title]) => title.toLowerCase().includes(searchTerm.toLowerCase()))
-    .sort((a, b) => {
+  // Search results keep the backend's ranking; otherwise sort as selected
+  const entriesById = new Map(entries.map((entry) => [entry[0], entry] as const));
+  const filteredEntries = searchResults
+    ? searchResults
+        .map((id) => entriesById.get(id))
+        .filter((entry): entry is [string, string] => entry !== undefined)
+    : [...entries].sort((a, b) => {
       const [idA, titleA] = a;
       const [idB, titleB] = b;
       
@@ -347,7 +397,7 @@ function App() {
                 )}
               </Button>
               <Button 
-                onClick={onUnlock} 
+                onClick={() => onUnlock()} 
                 className="flex-1 bg-gradient-to-r from-indigo-500 to-blue-600 text-white shadow-md hover:shadow-lg"
                 disabled={isUnlocking}
               >
@@ -0,0 +1,43 @@
+// Picks Argon2id costs for the machine it runs on by timing `derive_kek`.
+// Memory is spent first, since that is what makes guessing expensive on GPUs;
+// iterations then fill whatever time is left.
+
+use crate::{derive_kek, ArgonParams, CryptoError};
+use std::time::{Duration, Instant};
+
+// Lanes per hash. The time of one hash does not depend on it, as lanes are
+// computed one after the other.
+pub const CALIBRATION_PARALLELISM: u32 = 4;
+// Memory is not halved below this while looking for a single pass that fits
+// the target (OWASP's minimum for Argon2id)
+pub const MIN_CALIBRATION_MEM_KIB: u32 = 19 * 1024;
+
+// Largest costs whose `derive_kek` takes about `target` here, using at most
+// `max_mem_kib` of memory and never less than `min` of any cost. Memory is
+// lowered until `min.iterations` passes fit the target, so the minimum is
+// part of the search rather than added on top of it. If even that does not
+// fit, the minimum is returned anyway: it is the floor, not a target to meet.
+pub fn calibrate_argon2(target: Duration, max_mem_kib: u32, min: &ArgonParams) -> Result<ArgonParams, CryptoError> {
+    let parallelism = CALIBRATION_PARALLELISM.max(min.parallelism);
+    let max_mem_kib = max_mem_kib.max(min.mem_kib);
+    if max_mem_kib < 8 * parallelism {
+        return Err(CryptoError::InvalidKdfParams);
+    }
+    let floor = MIN_CALIBRATION_MEM_KIB.min(max_mem_kib).max(min.mem_kib);
+    let min_passes = min.iterations.max(1);
+    let mut params = ArgonParams { mem_kib: max_mem_kib, iterations: 1, parallelism };
+    let mut pass = time_kdf(&params)?;
+    while pass.saturating_mul(min_passes) > target && params.mem_kib > floor {
+        params.mem_kib = (params.mem_kib / 2).max(floor);
+        pass = time_kdf(&params)?;
+    }
+    let passes = target.as_nanos() / pass.as_nanos().max(1);
+    params.iterations = u32::try_from(passes).unwrap_or(u32::MAX).max(min_passes);
+    Ok(params)
+}
+
+fn time_kdf(params: &ArgonParams) -> Result<Duration, CryptoError> {
+    let start = Instant::now();
+    derive_kek(b"calibration", params, &[0u8; 32])?;
+    Ok(start.elapsed())
+}
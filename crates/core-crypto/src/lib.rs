@@ -1,94 +1,416 @@
 use argon2::{Algorithm, Argon2, Params, Version};
 use aes_gcm::aead::{Aead, KeyInit};
 use aes_gcm::{Aes256Gcm, Nonce};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
 use hkdf::Hkdf;
 use sha2::Sha256;
 use zeroize::Zeroize;
 use rand::Rng;
+use serde::{Deserialize, Serialize};
 
+mod calibrate;
+mod stream;
+pub use calibrate::{calibrate_argon2, CALIBRATION_PARALLELISM, MIN_CALIBRATION_MEM_KIB};
+pub use stream::{StreamNonce, STREAM_CHUNK_SIZE, STREAM_PREFIX_LEN};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CryptoError {
+    /// Argon2 rejected the supplied memory/iteration/parallelism settings.
+    InvalidKdfParams,
+    /// AEAD tag or signature did not verify (wrong key or tampered data).
+    AuthenticationFailed,
+    /// Input was too short, too long or otherwise not in the expected shape.
+    MalformedInput,
+    /// Key material could not be used (e.g. not a valid Ed25519 point).
+    InvalidKey,
+}
+
+impl std::fmt::Display for CryptoError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let msg = match self {
+            CryptoError::InvalidKdfParams => "invalid KDF parameters",
+            CryptoError::AuthenticationFailed => "decryption failure - wrong password or corrupted data",
+            CryptoError::MalformedInput => "malformed input",
+            CryptoError::InvalidKey => "invalid key",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ArgonParams {
     pub mem_kib: u32,
     pub iterations: u32,
     pub parallelism: u32,
 }
 
-pub fn derive_kek(master: &[u8], params: &ArgonParams, salt: &[u8; 32]) -> [u8; 32] {
-    let argon_params = Params::new(params.mem_kib, params.iterations, params.parallelism, Some(32)).expect("valid argon2 params");
+pub fn derive_kek(master: &[u8], params: &ArgonParams, salt: &[u8; 32]) -> Result<[u8; 32], CryptoError> {
+    let argon_params = Params::new(params.mem_kib, params.iterations, params.parallelism, Some(32))
+        .map_err(|_| CryptoError::InvalidKdfParams)?;
     let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, argon_params);
     let mut out = [0u8; 32];
     argon2
         .hash_password_into(master, salt, &mut out)
-        .expect("argon2 hash");
-    out
+        .map_err(|_| CryptoError::InvalidKdfParams)?;
+    Ok(out)
 }
 
 // Returns nonce(12) || ciphertext+tag using AES-256-GCM
-pub fn wrap_key_aes_gcm(kek: &[u8; 32], dek: &[u8; 32], nonce12: &[u8; 12]) -> Vec<u8> {
+pub fn wrap_key_aes_gcm(kek: &[u8; 32], dek: &[u8; 32], nonce12: &[u8; 12]) -> Result<Vec<u8>, CryptoError> {
     let cipher = Aes256Gcm::new(kek.into());
     let nonce = Nonce::from_slice(nonce12);
     let ct = cipher
         .encrypt(nonce, dek.as_slice())
-        .expect("encryption failure");
+        .map_err(|_| CryptoError::MalformedInput)?;
     let mut out = Vec::with_capacity(12 + ct.len());
     out.extend_from_slice(nonce12);
     out.extend_from_slice(&ct);
-    out
+    Ok(out)
 }
 
-pub fn unwrap_key_aes_gcm(kek: &[u8; 32], ct_with_nonce: &[u8]) -> Result<[u8; 32], String> {
+pub fn unwrap_key_aes_gcm(kek: &[u8; 32], ct_with_nonce: &[u8]) -> Result<[u8; 32], CryptoError> {
     if ct_with_nonce.len() < 12 + 16 {
-        return Err("ciphertext too short".to_string());
+        return Err(CryptoError::MalformedInput);
     }
     let (nonce_bytes, ct) = ct_with_nonce.split_at(12);
     let cipher = Aes256Gcm::new(kek.into());
     let nonce = Nonce::from_slice(nonce_bytes);
     let mut dek = cipher
         .decrypt(nonce, ct)
-        .map_err(|_| "decryption failure - wrong password or corrupted data".to_string())?;
+        .map_err(|_| CryptoError::AuthenticationFailed)?;
+    if dek.len() != 32 {
+        dek.zeroize();
+        return Err(CryptoError::MalformedInput);
+    }
     let mut out = [0u8; 32];
-    out.copy_from_slice(&dek[..32]);
+    out.copy_from_slice(&dek);
     dek.zeroize();
     Ok(out)
 }
 
 // Generic AES-GCM helpers for arbitrary plaintext
-pub fn aead_encrypt_aes_gcm(key: &[u8; 32], nonce12: &[u8; 12], plaintext: &[u8], aad: &[u8]) -> Vec<u8> {
+pub fn aead_encrypt_aes_gcm(key: &[u8; 32], nonce12: &[u8; 12], plaintext: &[u8], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
     let cipher = Aes256Gcm::new(key.into());
     let nonce = Nonce::from_slice(nonce12);
-    cipher.encrypt(nonce, aes_gcm::aead::Payload { msg: plaintext, aad }).expect("encryption failure")
+    cipher
+        .encrypt(nonce, aes_gcm::aead::Payload { msg: plaintext, aad })
+        .map_err(|_| CryptoError::MalformedInput)
 }
 
-pub fn aead_decrypt_aes_gcm(key: &[u8; 32], ct: &[u8], nonce12: &[u8; 12], aad: &[u8]) -> Vec<u8> {
+pub fn aead_decrypt_aes_gcm(key: &[u8; 32], ct: &[u8], nonce12: &[u8; 12], aad: &[u8]) -> Result<Vec<u8>, CryptoError> {
     let cipher = Aes256Gcm::new(key.into());
     let nonce = Nonce::from_slice(nonce12);
-    cipher.decrypt(nonce, aes_gcm::aead::Payload { msg: ct, aad }).expect("decryption failure")
+    cipher
+        .decrypt(nonce, aes_gcm::aead::Payload { msg: ct, aad })
+        .map_err(|_| CryptoError::AuthenticationFailed)
+}
+
+// XChaCha20-Poly1305 helpers; the 192-bit nonce is safe to pick at random for
+// any practical number of messages under one key
+pub fn aead_encrypt_xchacha20poly1305(
+    key: &[u8; 32],
+    nonce24: &[u8; 24],
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    let cipher = XChaCha20Poly1305::new(key.into());
+    cipher
+        .encrypt(XNonce::from_slice(nonce24), aes_gcm::aead::Payload { msg: plaintext, aad })
+        .map_err(|_| CryptoError::MalformedInput)
+}
+
+pub fn aead_decrypt_xchacha20poly1305(
+    key: &[u8; 32],
+    ct: &[u8],
+    nonce24: &[u8; 24],
+    aad: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    let cipher = XChaCha20Poly1305::new(key.into());
+    cipher
+        .decrypt(XNonce::from_slice(nonce24), aes_gcm::aead::Payload { msg: ct, aad })
+        .map_err(|_| CryptoError::AuthenticationFailed)
+}
+
+// AEAD used for a vault's key slots and records. Stored in the vault header by
+// variant index, so new suites must be added at the end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum CipherSuite {
+    // 96-bit random nonces; every vault written before cipher suites existed
+    #[default]
+    Aes256Gcm,
+    // 192-bit random nonces, and fast on CPUs without AES instructions
+    XChaCha20Poly1305,
+}
+
+impl CipherSuite {
+    pub fn nonce_len(self) -> usize {
+        match self {
+            CipherSuite::Aes256Gcm => 12,
+            CipherSuite::XChaCha20Poly1305 => 24,
+        }
+    }
+
+    pub fn random_nonce(self) -> Vec<u8> {
+        let mut nonce = vec![0u8; self.nonce_len()];
+        rand::thread_rng().fill(nonce.as_mut_slice());
+        nonce
+    }
+}
+
+// `nonce` must be `suite.nonce_len()` bytes
+pub fn aead_encrypt(
+    suite: CipherSuite,
+    key: &[u8; 32],
+    nonce: &[u8],
+    plaintext: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    match suite {
+        CipherSuite::Aes256Gcm => {
+            let nonce = nonce.try_into().map_err(|_| CryptoError::MalformedInput)?;
+            aead_encrypt_aes_gcm(key, nonce, plaintext, aad)
+        }
+        CipherSuite::XChaCha20Poly1305 => {
+            let nonce = nonce.try_into().map_err(|_| CryptoError::MalformedInput)?;
+            aead_encrypt_xchacha20poly1305(key, nonce, plaintext, aad)
+        }
+    }
+}
+
+pub fn aead_decrypt(
+    suite: CipherSuite,
+    key: &[u8; 32],
+    ct: &[u8],
+    nonce: &[u8],
+    aad: &[u8],
+) -> Result<Vec<u8>, CryptoError> {
+    match suite {
+        CipherSuite::Aes256Gcm => {
+            let nonce = nonce.try_into().map_err(|_| CryptoError::MalformedInput)?;
+            aead_decrypt_aes_gcm(key, ct, nonce, aad)
+        }
+        CipherSuite::XChaCha20Poly1305 => {
+            let nonce = nonce.try_into().map_err(|_| CryptoError::MalformedInput)?;
+            aead_decrypt_xchacha20poly1305(key, ct, nonce, aad)
+        }
+    }
+}
+
+// Returns nonce || ciphertext+tag, with a random nonce of the suite's length
+pub fn wrap_key(suite: CipherSuite, kek: &[u8; 32], dek: &[u8; 32]) -> Result<Vec<u8>, CryptoError> {
+    let nonce = suite.random_nonce();
+    let ct = aead_encrypt(suite, kek, &nonce, dek, &[])?;
+    let mut out = Vec::with_capacity(nonce.len() + ct.len());
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&ct);
+    Ok(out)
+}
+
+pub fn unwrap_key(suite: CipherSuite, kek: &[u8; 32], ct_with_nonce: &[u8]) -> Result<[u8; 32], CryptoError> {
+    if ct_with_nonce.len() < suite.nonce_len() + 16 {
+        return Err(CryptoError::MalformedInput);
+    }
+    let (nonce, ct) = ct_with_nonce.split_at(suite.nonce_len());
+    let mut dek = aead_decrypt(suite, kek, ct, nonce, &[])?;
+    if dek.len() != 32 {
+        dek.zeroize();
+        return Err(CryptoError::MalformedInput);
+    }
+    let mut out = [0u8; 32];
+    out.copy_from_slice(&dek);
+    dek.zeroize();
+    Ok(out)
 }
 
-pub fn derive_item_key(dek: &[u8; 32], item_id: &[u8; 16]) -> [u8; 32] {
+pub fn derive_item_key(dek: &[u8; 32], item_id: &[u8; 16]) -> Result<[u8; 32], CryptoError> {
     const INFO_PREFIX: &[u8] = b"item";
     let mut info = Vec::with_capacity(INFO_PREFIX.len() + item_id.len());
     info.extend_from_slice(INFO_PREFIX);
     info.extend_from_slice(item_id);
     let hk = Hkdf::<Sha256>::new(Some(&[]), dek);
     let mut okm = [0u8; 32];
-    hk.expand(&info, &mut okm).expect("hkdf expand");
-    okm
+    hk.expand(&info, &mut okm).map_err(|_| CryptoError::InvalidKey)?;
+    Ok(okm)
+}
+
+// Keyfiles: random bytes kept outside the vault as a second unlock factor
+pub const KEYFILE_LEN: usize = 64;
+
+pub fn generate_keyfile() -> Vec<u8> {
+    let mut keyfile = vec![0u8; KEYFILE_LEN];
+    rand::thread_rng().fill(keyfile.as_mut_slice());
+    keyfile
+}
+
+// Mix the Argon2id KEK with a hash of the keyfile contents so that neither
+// the password nor the keyfile alone can unwrap the DEK
+pub fn combine_kek_with_keyfile(password_kek: &[u8; 32], keyfile: &[u8]) -> Result<[u8; 32], CryptoError> {
+    use sha2::Digest;
+    if keyfile.is_empty() {
+        return Err(CryptoError::MalformedInput);
+    }
+    let keyfile_hash = Sha256::digest(keyfile);
+    let mut ikm = [0u8; 64];
+    ikm[..32].copy_from_slice(password_kek);
+    ikm[32..].copy_from_slice(&keyfile_hash);
+    let hk = Hkdf::<Sha256>::new(None, &ikm);
+    ikm.zeroize();
+    let mut okm = [0u8; 32];
+    hk.expand(b"kek+keyfile", &mut okm).map_err(|_| CryptoError::InvalidKey)?;
+    Ok(okm)
+}
+
+// Record chaining: every record's MAC covers the previous MAC, so dropping,
+// reordering or replaying records changes every MAC after it
+pub fn derive_chain_key(dek: &[u8; 32]) -> Result<[u8; 32], CryptoError> {
+    let hk = Hkdf::<Sha256>::new(Some(&[]), dek);
+    let mut okm = [0u8; 32];
+    hk.expand(b"record-chain", &mut okm).map_err(|_| CryptoError::InvalidKey)?;
+    Ok(okm)
+}
+
+// HMAC-SHA256(chain_key, prev || record)
+pub fn chain_mac(chain_key: &[u8; 32], prev: &[u8; 32], record: &[u8]) -> Result<[u8; 32], CryptoError> {
+    use hmac::{Hmac, Mac};
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(chain_key).map_err(|_| CryptoError::InvalidKey)?;
+    mac.update(prev);
+    mac.update(record);
+    Ok(mac.finalize().into_bytes().into())
+}
+
+// Constant-time comparison for MACs computed outside the AEAD
+pub fn macs_equal(a: &[u8; 32], b: &[u8; 32]) -> bool {
+    use subtle::ConstantTimeEq;
+    a.ct_eq(b).into()
 }
 
 // Ed25519 helpers (header signing)
 use ed25519_dalek::{Signature, SigningKey, VerifyingKey};
 use ed25519_dalek::Signer;
 
+// Header signing keys are derived from the DEK, so only someone who can
+// unlock the vault can produce a valid header signature
+pub fn derive_header_signing_key(dek: &[u8; 32]) -> Result<[u8; 32], CryptoError> {
+    let hk = Hkdf::<Sha256>::new(Some(&[]), dek);
+    let mut okm = [0u8; 32];
+    hk.expand(b"header-sign", &mut okm).map_err(|_| CryptoError::InvalidKey)?;
+    Ok(okm)
+}
+
+pub fn header_verify_key(signing_key_bytes: &[u8; 32]) -> [u8; 32] {
+    SigningKey::from_bytes(signing_key_bytes).verifying_key().to_bytes()
+}
+
 pub fn sign_header(signing_key_bytes: &[u8; 32], header_bytes: &[u8]) -> [u8; 64] {
     let signing_key = SigningKey::from_bytes(signing_key_bytes);
     let sig: Signature = signing_key.sign(header_bytes);
     sig.to_bytes()
 }
 
-pub fn verify_header(verify_key_bytes: &[u8; 32], header_bytes: &[u8], sig_bytes: &[u8; 64]) -> bool {
-    let verifying_key = VerifyingKey::from_bytes(verify_key_bytes).expect("valid pubkey");
+pub fn verify_header(verify_key_bytes: &[u8; 32], header_bytes: &[u8], sig_bytes: &[u8; 64]) -> Result<(), CryptoError> {
+    let verifying_key = VerifyingKey::from_bytes(verify_key_bytes).map_err(|_| CryptoError::InvalidKey)?;
     let sig = Signature::from_bytes(sig_bytes);
-    verifying_key.verify_strict(header_bytes, &sig).is_ok()
+    verifying_key
+        .verify_strict(header_bytes, &sig)
+        .map_err(|_| CryptoError::AuthenticationFailed)
+}
+
+// Recovery keys: 32 random bytes plus a 3-byte SHA-256 checksum, printed as
+// seven dash-separated groups of eight base32 characters (RFC 4648 alphabet).
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+const RECOVERY_CHECKSUM_LEN: usize = 3;
+const RECOVERY_GROUP_LEN: usize = 8;
+
+pub fn generate_recovery_key() -> String {
+    let mut key = [0u8; 32];
+    rand::thread_rng().fill(&mut key);
+    let encoded = encode_recovery_key(&key);
+    key.zeroize();
+    encoded
+}
+
+pub fn encode_recovery_key(key: &[u8; 32]) -> String {
+    let mut raw = Vec::with_capacity(32 + RECOVERY_CHECKSUM_LEN);
+    raw.extend_from_slice(key);
+    raw.extend_from_slice(&recovery_checksum(key));
+    let chars = base32_encode(&raw);
+    raw.zeroize();
+    let groups: Vec<&str> = chars
+        .as_bytes()
+        .chunks(RECOVERY_GROUP_LEN)
+        .map(|g| std::str::from_utf8(g).unwrap_or_default())
+        .collect();
+    groups.join("-")
+}
+
+// Accepts any case and ignores dashes and whitespace between groups
+pub fn parse_recovery_key(printed: &str) -> Result<[u8; 32], CryptoError> {
+    let chars: String = printed
+        .chars()
+        .filter(|c| *c != '-' && !c.is_whitespace())
+        .map(|c| c.to_ascii_uppercase())
+        .collect();
+    let mut raw = base32_decode(&chars)?;
+    if raw.len() != 32 + RECOVERY_CHECKSUM_LEN {
+        raw.zeroize();
+        return Err(CryptoError::MalformedInput);
+    }
+    let mut key = [0u8; 32];
+    key.copy_from_slice(&raw[..32]);
+    let ok = raw[32..] == recovery_checksum(&key);
+    raw.zeroize();
+    if !ok {
+        key.zeroize();
+        return Err(CryptoError::MalformedInput);
+    }
+    Ok(key)
+}
+
+fn recovery_checksum(key: &[u8; 32]) -> [u8; RECOVERY_CHECKSUM_LEN] {
+    use sha2::Digest;
+    let digest = Sha256::digest(key);
+    let mut out = [0u8; RECOVERY_CHECKSUM_LEN];
+    out.copy_from_slice(&digest[..RECOVERY_CHECKSUM_LEN]);
+    out
+}
+
+fn base32_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity(data.len().div_ceil(5) * 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for &b in data {
+        buffer = (buffer << 8) | b as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 0x1f) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 0x1f) as usize] as char);
+    }
+    out
+}
+
+fn base32_decode(text: &str) -> Result<Vec<u8>, CryptoError> {
+    let mut out = Vec::with_capacity(text.len() * 5 / 8);
+    let mut buffer: u32 = 0;
+    let mut bits = 0;
+    for c in text.bytes() {
+        let v = BASE32_ALPHABET
+            .iter()
+            .position(|&a| a == c)
+            .ok_or(CryptoError::MalformedInput)? as u32;
+        buffer = (buffer << 5) | v;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Ok(out)
 }
 
 // Password Generator
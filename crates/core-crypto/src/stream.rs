@@ -0,0 +1,53 @@
+// Nonces for the STREAM online AEAD construction (Hoang, Reyhanitabar, Rogaway
+// and Vizár). Plaintext is cut into STREAM_CHUNK_SIZE chunks that are sealed one
+// at a time with the vault's cipher suite, so data of any size is encrypted and
+// decrypted in constant memory. The nonce of each chunk carries its position
+// and whether it is the last one, so a reader that checks them notices chunks
+// that are dropped, reordered, duplicated or appended. The vault stores each
+// chunk of an attachment as a record of its own, sealed with this nonce.
+
+use rand::Rng;
+
+// Plaintext bytes per chunk. Only the last chunk may be shorter; an empty
+// stream is a single empty last chunk.
+pub const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+pub const STREAM_PREFIX_LEN: usize = 7;
+
+// Nonce of one chunk: prefix(7) | counter(4, BE) | last(1). The prefix is
+// random per stream, so one key can seal many streams.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StreamNonce {
+    pub prefix: [u8; STREAM_PREFIX_LEN],
+    pub counter: u32,
+    pub last: bool,
+}
+
+impl StreamNonce {
+    pub fn random_prefix() -> [u8; STREAM_PREFIX_LEN] {
+        let mut prefix = [0u8; STREAM_PREFIX_LEN];
+        rand::thread_rng().fill(&mut prefix);
+        prefix
+    }
+
+    pub fn to_bytes(self) -> [u8; 12] {
+        let mut out = [0u8; 12];
+        out[..STREAM_PREFIX_LEN].copy_from_slice(&self.prefix);
+        out[7..11].copy_from_slice(&self.counter.to_be_bytes());
+        out[11] = u8::from(self.last);
+        out
+    }
+
+    // None if the last-chunk flag is neither 0 nor 1
+    pub fn parse(bytes: &[u8; 12]) -> Option<Self> {
+        let last = match bytes[11] {
+            0 => false,
+            1 => true,
+            _ => return None,
+        };
+        Some(Self {
+            prefix: bytes[..STREAM_PREFIX_LEN].try_into().expect("7 bytes"),
+            counter: u32::from_be_bytes(bytes[7..11].try_into().expect("4 bytes")),
+            last,
+        })
+    }
+}
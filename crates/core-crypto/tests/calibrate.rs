@@ -0,0 +1,55 @@
+use core_crypto::{
+    calibrate_argon2, derive_kek, ArgonParams, CryptoError, CALIBRATION_PARALLELISM, MIN_CALIBRATION_MEM_KIB,
+};
+use std::time::{Duration, Instant};
+
+const NO_MINIMUM: ArgonParams = ArgonParams { mem_kib: 0, iterations: 0, parallelism: 0 };
+
+#[test]
+fn calibrated_params_stay_within_the_memory_cap() {
+    let params = calibrate_argon2(Duration::from_millis(200), 8 * 1024, &NO_MINIMUM).unwrap();
+    assert_eq!(params.mem_kib, 8 * 1024);
+    assert_eq!(params.parallelism, CALIBRATION_PARALLELISM);
+    assert!(params.iterations >= 1);
+
+    // Generous bound: this only catches costs that are wildly off
+    let start = Instant::now();
+    derive_kek(b"pw", &params, &[1u8; 32]).unwrap();
+    assert!(start.elapsed() < Duration::from_secs(2), "{:?} took {:?}", params, start.elapsed());
+}
+
+#[test]
+fn a_target_shorter_than_one_pass_still_gets_one_iteration() {
+    let params = calibrate_argon2(Duration::ZERO, 64, &NO_MINIMUM).unwrap();
+    assert_eq!((params.mem_kib, params.iterations), (64, 1));
+    assert_eq!(calibrate_argon2(Duration::from_secs(1), 16, &NO_MINIMUM).err(), Some(CryptoError::InvalidKdfParams));
+}
+
+#[test]
+fn the_search_starts_at_the_minimum() {
+    // Too short for anything: memory stops at the calibration floor and
+    // passes at the minimum
+    let min = ArgonParams { mem_kib: 512, iterations: 3, parallelism: 1 };
+    let params = calibrate_argon2(Duration::ZERO, 4 * 1024, &min).unwrap();
+    assert_eq!(params, ArgonParams { mem_kib: 4 * 1024, iterations: 3, parallelism: CALIBRATION_PARALLELISM });
+
+    // A minimum above the memory cap wins over the cap
+    let min = ArgonParams { mem_kib: 2 * 1024, iterations: 1, parallelism: 8 };
+    let params = calibrate_argon2(Duration::ZERO, 1024, &min).unwrap();
+    assert_eq!(params, ArgonParams { mem_kib: 2 * 1024, iterations: 1, parallelism: 8 });
+
+    // Room for one pass over the most memory but not for two: memory goes
+    // down until the minimum of two passes fits, rather than two passes
+    // being forced onto the most memory afterwards
+    let min = ArgonParams { mem_kib: 1024, iterations: 2, parallelism: 1 };
+    let most = ArgonParams { mem_kib: 32 * 1024, iterations: 1, parallelism: CALIBRATION_PARALLELISM };
+    let params = calibrate_argon2(time(&most).mul_f32(1.6), most.mem_kib, &min).unwrap();
+    assert_eq!(params.mem_kib, MIN_CALIBRATION_MEM_KIB);
+    assert!(params.iterations >= 2, "{:?}", params);
+}
+
+fn time(params: &ArgonParams) -> Duration {
+    let start = Instant::now();
+    derive_kek(b"pw", params, &[1u8; 32]).unwrap();
+    start.elapsed()
+}
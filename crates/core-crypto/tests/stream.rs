@@ -0,0 +1,29 @@
+use core_crypto::{StreamNonce, STREAM_PREFIX_LEN};
+
+#[test]
+fn nonces_round_trip_through_their_bytes() {
+    let prefix = StreamNonce::random_prefix();
+    for (counter, last) in [(0, false), (0, true), (1, false), (u32::MAX, true)] {
+        let nonce = StreamNonce { prefix, counter, last };
+        let bytes = nonce.to_bytes();
+        assert_eq!(bytes[..STREAM_PREFIX_LEN], prefix);
+        assert_eq!(StreamNonce::parse(&bytes), Some(nonce));
+    }
+}
+
+#[test]
+fn position_and_last_flag_give_distinct_nonces() {
+    let prefix = [3u8; STREAM_PREFIX_LEN];
+    let nonce = |counter, last| StreamNonce { prefix, counter, last }.to_bytes();
+    assert_ne!(nonce(0, false), nonce(0, true));
+    assert_ne!(nonce(0, false), nonce(1, false));
+    // The counter is big-endian, right after the prefix
+    assert_eq!(nonce(0x0102_0304, true)[STREAM_PREFIX_LEN..], [1, 2, 3, 4, 1]);
+}
+
+#[test]
+fn rejects_a_last_flag_other_than_0_or_1() {
+    let mut bytes = StreamNonce { prefix: [0; STREAM_PREFIX_LEN], counter: 5, last: true }.to_bytes();
+    bytes[11] = 2;
+    assert_eq!(StreamNonce::parse(&bytes), None);
+}
@@ -0,0 +1,211 @@
+use crate::lock::LockMode;
+use crate::record::{
+    encode_chunk, encode_record, live_attachments, live_ids, tombstone_payload, ChunkReader, Format, RecordKind,
+    RecordRef,
+};
+use crate::{to_io_err, unix_now, UnlockedVault, VaultStore};
+use core_crypto::{StreamNonce, STREAM_CHUNK_SIZE};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+// A file stored with an entry, such as an SSH key or a license file. Its
+// content is held in chunk records written just before this one, so a crash
+// part-way through adding an attachment leaves no attachment at all.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Attachment {
+    pub id: Uuid,
+    pub entry_id: Uuid,
+    pub name: String,
+    pub size: u64,     // bytes
+    pub added_at: u64, // unix seconds
+}
+
+// Attachment payload: postcard(Attachment)
+pub(crate) fn encode_attachment(attachment: &Attachment) -> std::io::Result<Vec<u8>> {
+    postcard::to_stdvec(attachment).map_err(to_io_err)
+}
+
+pub(crate) fn decode_attachment(pt: &[u8]) -> std::io::Result<Attachment> {
+    postcard::from_bytes(pt).map_err(to_io_err)
+}
+
+impl VaultStore {
+    // Stream `content` into the vault as an attachment of the live entry
+    // `entry_id`, one chunk record at a time
+    pub fn add_attachment<R: Read>(
+        &self,
+        dek: &[u8; 32],
+        entry_id: Uuid,
+        name: &str,
+        content: R,
+    ) -> std::io::Result<Attachment> {
+        self.ensure_current()?;
+        let _lock = self.lock(LockMode::Exclusive)?;
+        if !live_ids(&self.read_records(dek)?).contains_key(&entry_id) {
+            return Err(entry_not_found());
+        }
+        self.append_attachment(dek, None, entry_id, name, content).map(|(attachment, _, _)| attachment)
+    }
+
+    // Attachments of `entry_id`, oldest first. An entry in the trash keeps its
+    // attachments, listed and extractable, until it is purged, so restoring
+    // it brings them back.
+    pub fn list_attachments(&self, dek: &[u8; 32], entry_id: Uuid) -> std::io::Result<Vec<Attachment>> {
+        let _lock = self.lock(LockMode::Shared)?;
+        Ok(live_attachments(&self.read_records(dek)?)
+            .into_iter()
+            .map(|(a, _)| a)
+            .filter(|a| a.entry_id == entry_id)
+            .collect())
+    }
+
+    // Decrypt attachment `id` into `out` one chunk at a time. Returns the
+    // number of bytes written.
+    pub fn extract_attachment<W: Write>(&self, dek: &[u8; 32], id: Uuid, mut out: W) -> std::io::Result<u64> {
+        let _lock = self.lock(LockMode::Shared)?;
+        let (attachment, chunks) = live_attachments(&self.read_records(dek)?)
+            .into_iter()
+            .find(|(a, _)| a.id == id)
+            .ok_or_else(attachment_not_found)?;
+        self.write_chunks(dek, &attachment, &chunks, &mut out)
+    }
+
+    // Its chunks stay in the log until the next compaction
+    pub fn remove_attachment(&self, dek: &[u8; 32], id: Uuid) -> std::io::Result<()> {
+        self.ensure_current()?;
+        let _lock = self.lock(LockMode::Exclusive)?;
+        if !live_attachments(&self.read_records(dek)?).iter().any(|(a, _)| a.id == id) {
+            return Err(attachment_not_found());
+        }
+        self.append_record_locked(dek, None, RecordKind::Tombstone, id, &tombstone_payload(unix_now())).map(|_| ())
+    }
+
+    // Write the chunks and then the attachment record that commits them, as
+    // one append: a crash keeps all of them or none. Returns the attachment, where its chunks went and
+    // the new chain head. The caller must hold the exclusive lock; see
+    // `begin_append` for `expected_head`.
+    pub(crate) fn append_attachment<R: Read>(
+        &self,
+        dek: &[u8; 32],
+        expected_head: Option<[u8; 32]>,
+        entry_id: Uuid,
+        name: &str,
+        mut content: R,
+    ) -> std::io::Result<(Attachment, Vec<RecordRef>, [u8; 32])> {
+        let id = Uuid::new_v4();
+        let suite = self.header.cipher_suite;
+        let prefix = StreamNonce::random_prefix();
+        let mut appender = self.begin_append(dek, expected_head)?;
+        let mut chunks = Vec::new();
+        let mut size = 0u64;
+        let mut counter = 0u32;
+        let mut data = read_chunk(&mut content)?;
+        loop {
+            // Read one chunk ahead to know whether this one is the last
+            let next = if data.len() == STREAM_CHUNK_SIZE { read_chunk(&mut content)? } else { Zeroizing::new(Vec::new()) };
+            let nonce = StreamNonce { prefix, counter, last: next.is_empty() };
+            chunks.push(appender.push(encode_chunk(dek, suite, id, nonce, &data, &appender.head())?)?);
+            size += data.len() as u64;
+            if nonce.last {
+                break;
+            }
+            data = next;
+            counter = counter
+                .checked_add(1)
+                .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "attachment is too large"))?;
+        }
+        let attachment = Attachment { id, entry_id, name: name.to_string(), size, added_at: unix_now() };
+        let payload = encode_attachment(&attachment)?;
+        appender.push(encode_record(dek, suite, RecordKind::Attachment, id, &payload, &appender.head())?)?;
+        Ok((attachment, chunks, appender.finish()?))
+    }
+
+    // Decrypt `chunks` into `out`, checking that they form the whole STREAM of
+    // `attachment` in order. The caller must hold the lock.
+    fn write_chunks(
+        &self,
+        dek: &[u8; 32],
+        attachment: &Attachment,
+        chunks: &[RecordRef],
+        out: &mut dyn Write,
+    ) -> std::io::Result<u64> {
+        let mut reader = ChunkReader::open(&self.path, Format::of(&self.header))?;
+        let mut prefix = None;
+        let mut written = 0u64;
+        for (i, at) in chunks.iter().enumerate() {
+            let (id, nonce, data) = reader.read(dek, *at)?;
+            let first_prefix = *prefix.get_or_insert(nonce.prefix);
+            let in_order = u32::try_from(i) == Ok(nonce.counter)
+                && nonce.prefix == first_prefix
+                && nonce.last == (i + 1 == chunks.len());
+            if id != attachment.id || !in_order {
+                return Err(incomplete_attachment());
+            }
+            out.write_all(&data)?;
+            written += data.len() as u64;
+        }
+        if chunks.is_empty() || written != attachment.size {
+            return Err(incomplete_attachment());
+        }
+        out.flush()?;
+        Ok(written)
+    }
+}
+
+impl UnlockedVault {
+    pub fn add_attachment<R: Read>(&mut self, entry_id: Uuid, name: &str, content: R) -> std::io::Result<Attachment> {
+        let _lock = self.lock_for_append()?;
+        if self.index.get(entry_id).is_none() {
+            return Err(entry_not_found());
+        }
+        let (attachment, chunks, head) =
+            self.store.append_attachment(&self.dek, self.index.head(), entry_id, name, content)?;
+        self.index.appended_attachment(&attachment, chunks, head);
+        Ok(attachment)
+    }
+
+    // Served from the index, like `list_entries`
+    pub fn list_attachments(&mut self, entry_id: Uuid) -> std::io::Result<Vec<Attachment>> {
+        let _lock = self.sync_index()?;
+        Ok(self.index.attachments_of(entry_id))
+    }
+
+    pub fn extract_attachment<W: Write>(&mut self, id: Uuid, mut out: W) -> std::io::Result<u64> {
+        let _lock = self.sync_index()?;
+        let (attachment, chunks) = self.index.attachment(id).ok_or_else(attachment_not_found)?;
+        self.store.write_chunks(&self.dek, attachment, chunks, &mut out)
+    }
+
+    pub fn remove_attachment(&mut self, id: Uuid) -> std::io::Result<()> {
+        let _lock = self.lock_for_append()?;
+        if self.index.attachment(id).is_none() {
+            return Err(attachment_not_found());
+        }
+        let payload = tombstone_payload(unix_now());
+        let (at, head) =
+            self.store.append_record_locked(&self.dek, self.index.head(), RecordKind::Tombstone, id, &payload)?;
+        self.index.appended_tombstone(id, at, head);
+        Ok(())
+    }
+}
+
+// Up to STREAM_CHUNK_SIZE bytes of `content`, fewer only once it runs out
+fn read_chunk<R: Read>(content: &mut R) -> std::io::Result<Zeroizing<Vec<u8>>> {
+    let mut chunk = Zeroizing::new(Vec::with_capacity(STREAM_CHUNK_SIZE));
+    content.take(STREAM_CHUNK_SIZE as u64).read_to_end(&mut chunk)?;
+    Ok(chunk)
+}
+
+fn entry_not_found() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::NotFound, "entry not found")
+}
+
+fn attachment_not_found() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::NotFound, "attachment not found")
+}
+
+fn incomplete_attachment() -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidData, "attachment chunks are missing or out of order")
+}
@@ -0,0 +1,152 @@
+use crate::record::{live_attachments, Record, RecordRef, Scan};
+use crate::search::{tokenize, SearchTokens};
+use crate::{Attachment, VaultEntry};
+use std::collections::HashMap;
+use uuid::Uuid;
+
+// Latest version of every live entry and where every attachment's chunks are,
+// built by one scan of an unlocked vault and kept up to date as it appends.
+// Lets `UnlockedVault` list and search entries without decrypting anything and
+// read one by decrypting one record.
+#[derive(Default)]
+pub(crate) struct EntryIndex {
+    // Chain head of the log this index reflects. Any other head in the trailer
+    // means another process wrote to the vault; None means not built yet.
+    head: Option<[u8; 32]>,
+    next_seq: u64,
+    live: HashMap<Uuid, Indexed>,
+    attachments: HashMap<Uuid, IndexedAttachment>,
+}
+
+struct Indexed {
+    // Log order of the latest version, so listings keep the file's order
+    seq: u64,
+    at: RecordRef,
+    title: String,
+    tokens: SearchTokens,
+}
+
+struct IndexedAttachment {
+    seq: u64,
+    attachment: Attachment,
+    chunks: Vec<RecordRef>,
+}
+
+impl EntryIndex {
+    pub(crate) fn build(scan: &Scan) -> Self {
+        let mut index = Self { head: Some(scan.head), ..Self::default() };
+        for (record, at) in scan.records.iter().zip(&scan.refs) {
+            match record {
+                Record::Entry(entry) => index.insert(entry, *at),
+                Record::Tombstone { id, .. } => index.remove(*id),
+                Record::Attachment(_) | Record::Chunk { .. } => {}
+            }
+        }
+        for (attachment, chunks) in live_attachments(&scan.records) {
+            index.insert_attachment(attachment, chunks);
+        }
+        index
+    }
+
+    // None until built
+    pub(crate) fn head(&self) -> Option<[u8; 32]> {
+        self.head
+    }
+
+    pub(crate) fn is_current(&self, head: Option<[u8; 32]>) -> bool {
+        self.head.is_some() && self.head == head
+    }
+
+    // Drop everything, so the next read rebuilds from the file
+    pub(crate) fn invalidate(&mut self) {
+        *self = Self::default();
+    }
+
+    // Account for an entry this process appended at `at`, moving the chain to
+    // `head`. If the append did not follow the indexed head, another process
+    // wrote in between and the index is rebuilt instead.
+    pub(crate) fn appended_entry(&mut self, entry: &VaultEntry, at: RecordRef, head: [u8; 32]) {
+        if self.follows(at, head) {
+            self.insert(entry, at);
+        }
+    }
+
+    pub(crate) fn appended_tombstone(&mut self, id: Uuid, at: RecordRef, head: [u8; 32]) {
+        if self.follows(at, head) {
+            self.remove(id);
+        }
+    }
+
+    // `chunks` are where the attachment's chunk records went, in order; the
+    // first one follows the indexed head
+    pub(crate) fn appended_attachment(&mut self, attachment: &Attachment, chunks: Vec<RecordRef>, head: [u8; 32]) {
+        if self.follows(chunks[0], head) {
+            self.insert_attachment(attachment.clone(), chunks);
+        }
+    }
+
+    pub(crate) fn get(&self, id: Uuid) -> Option<RecordRef> {
+        self.live.get(&id).map(|e| e.at)
+    }
+
+    pub(crate) fn list(&self) -> Vec<(Uuid, String)> {
+        let mut entries: Vec<(&Uuid, &Indexed)> = self.live.iter().collect();
+        entries.sort_unstable_by_key(|(_, e)| e.seq);
+        entries.into_iter().map(|(id, e)| (*id, e.title.clone())).collect()
+    }
+
+    // Attachments of `entry_id` in the order they were added
+    pub(crate) fn attachments_of(&self, entry_id: Uuid) -> Vec<Attachment> {
+        let mut found: Vec<&IndexedAttachment> =
+            self.attachments.values().filter(|a| a.attachment.entry_id == entry_id).collect();
+        found.sort_unstable_by_key(|a| a.seq);
+        found.into_iter().map(|a| a.attachment.clone()).collect()
+    }
+
+    pub(crate) fn attachment(&self, id: Uuid) -> Option<(&Attachment, &[RecordRef])> {
+        self.attachments.get(&id).map(|a| (&a.attachment, a.chunks.as_slice()))
+    }
+
+    // IDs of the entries matching every term of `query`, best match first.
+    // Ties keep log order.
+    pub(crate) fn search(&self, query: &str) -> Vec<Uuid> {
+        let terms = tokenize(query);
+        if terms.is_empty() {
+            return Vec::new();
+        }
+        let mut hits: Vec<(u32, u64, Uuid)> = self
+            .live
+            .iter()
+            .filter_map(|(id, e)| e.tokens.score(&terms).map(|score| (score, e.seq, *id)))
+            .collect();
+        hits.sort_unstable_by(|a, b| b.0.cmp(&a.0).then(a.1.cmp(&b.1)));
+        hits.into_iter().map(|(_, _, id)| id).collect()
+    }
+
+    fn follows(&mut self, at: RecordRef, head: [u8; 32]) -> bool {
+        if self.head != Some(at.prev) {
+            self.invalidate();
+            return false;
+        }
+        self.head = Some(head);
+        true
+    }
+
+    fn insert(&mut self, entry: &VaultEntry, at: RecordRef) {
+        let indexed = Indexed { seq: self.next_seq, at, title: entry.title.clone(), tokens: SearchTokens::of(entry) };
+        self.live.insert(entry.id, indexed);
+        self.next_seq += 1;
+    }
+
+    fn insert_attachment(&mut self, attachment: Attachment, chunks: Vec<RecordRef>) {
+        let indexed = IndexedAttachment { seq: self.next_seq, attachment, chunks };
+        self.attachments.insert(indexed.attachment.id, indexed);
+        self.next_seq += 1;
+    }
+
+    // Tombstones remove entries and attachments alike
+    fn remove(&mut self, id: Uuid) {
+        self.live.remove(&id);
+        self.attachments.remove(&id);
+    }
+}
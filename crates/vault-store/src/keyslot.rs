@@ -0,0 +1,310 @@
+use crate::{to_io_err, ArgonParamsOnDisk, VaultError, VaultHeader, VaultStore};
+use core_crypto::{combine_kek_with_keyfile, derive_kek, unwrap_key, wrap_key, ArgonParams, CipherSuite, CryptoError};
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+// Recovery keys carry 256 bits of entropy, so their slots only need a
+// moderate Argon2 cost to slow down online guessing
+pub fn recovery_key_params() -> ArgonParams {
+    ArgonParams { mem_kib: 19 * 1024, iterations: 2, parallelism: 1 }
+}
+
+// Lowest Argon2 costs a key slot may keep. A slot below any of them is
+// re-sealed with the costs raised to the minimum when it is unlocked through
+// `UnlockedVault::unlock_with_policy`. The default is OWASP's minimum for
+// Argon2id.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfPolicy {
+    pub min_mem_kib: u32,
+    pub min_iterations: u32,
+    pub min_parallelism: u32,
+}
+
+impl Default for KdfPolicy {
+    fn default() -> Self {
+        Self { min_mem_kib: 19 * 1024, min_iterations: 2, min_parallelism: 1 }
+    }
+}
+
+impl KdfPolicy {
+    pub fn allows(&self, params: &ArgonParams) -> bool {
+        params.mem_kib >= self.min_mem_kib
+            && params.iterations >= self.min_iterations
+            && params.parallelism >= self.min_parallelism
+    }
+
+    // The lowest costs the policy allows, for `calibrate_argon2` to search above
+    pub fn minimum(&self) -> ArgonParams {
+        ArgonParams {
+            mem_kib: self.min_mem_kib,
+            iterations: self.min_iterations,
+            parallelism: self.min_parallelism,
+        }
+    }
+
+    // `params` with every cost below the minimum raised to it
+    pub fn raise(&self, params: ArgonParams) -> ArgonParams {
+        ArgonParams {
+            mem_kib: params.mem_kib.max(self.min_mem_kib),
+            iterations: params.iterations.max(self.min_iterations),
+            parallelism: params.parallelism.max(self.min_parallelism),
+        }
+    }
+}
+
+// A key slot re-sealed under stronger KDF costs
+#[derive(Debug, Clone, Serialize)]
+pub struct KdfUpgrade {
+    pub slot_id: u32,
+    pub slot_type: SlotType,
+    pub from: ArgonParamsOnDisk,
+    pub to: ArgonParamsOnDisk,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SlotType {
+    MasterPassword,
+    RecoveryKey,
+    Keyfile,
+}
+
+// One way of unwrapping the DEK, LUKS-style. Every slot wraps the same DEK
+// under a KEK derived from its own secret, params and salt.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct KeySlot {
+    pub id: u32,
+    pub slot_type: SlotType,
+    pub kdf_params: ArgonParamsOnDisk,
+    pub salt: [u8; 32],
+    pub wrapped_dek: Vec<u8>, // nonce || ct, under the header's cipher suite
+}
+
+impl KeySlot {
+    // Wrap `dek` under a KEK derived from `credential` with a fresh salt.
+    // `suite` must be the cipher suite of the header the slot goes into.
+    pub fn seal(
+        id: u32,
+        credential: Credential,
+        params: ArgonParams,
+        suite: CipherSuite,
+        dek: &[u8; 32],
+    ) -> std::io::Result<Self> {
+        let mut salt = [0u8; 32];
+        getrandom::getrandom(&mut salt).map_err(to_io_err)?;
+        let kek = slot_kek(credential, &params, &salt).map_err(to_io_err)?;
+        let wrapped_dek = wrap_key(suite, &kek, dek).map_err(to_io_err)?;
+        Ok(Self { id, slot_type: credential.slot_type(), kdf_params: params.into(), salt, wrapped_dek })
+    }
+
+    pub fn open(&self, credential: Credential, suite: CipherSuite) -> Result<Zeroizing<[u8; 32]>, CryptoError> {
+        let params = ArgonParams::from(self.kdf_params.clone());
+        let kek = slot_kek(credential, &params, &self.salt)?;
+        Ok(Zeroizing::new(unwrap_key(suite, &kek, &self.wrapped_dek)?))
+    }
+}
+
+fn next_slot_id(header: &VaultHeader) -> u32 {
+    header.key_slots.iter().map(|s| s.id + 1).max().unwrap_or(0)
+}
+
+// Index in `header`, as re-read from disk, of the slot `opened` that a
+// credential was checked against. Fails with `VaultError::VaultChanged` if
+// another process has re-sealed or removed it since, as re-sealing it again
+// would undo that change.
+pub(crate) fn find_opened_slot(header: &VaultHeader, opened: &KeySlot) -> std::io::Result<usize> {
+    header
+        .key_slots
+        .iter()
+        .position(|s| s.id == opened.id && s.salt == opened.salt)
+        .ok_or_else(|| to_io_err(VaultError::VaultChanged))
+}
+
+// Argon2id over the secret, then HKDF with the keyfile hash when one is given
+fn slot_kek(credential: Credential, params: &ArgonParams, salt: &[u8; 32]) -> Result<Zeroizing<[u8; 32]>, CryptoError> {
+    let kek = Zeroizing::new(derive_kek(credential.secret(), params, salt)?);
+    match credential.keyfile() {
+        Some(keyfile) => Ok(Zeroizing::new(combine_kek_with_keyfile(&kek, keyfile)?)),
+        None => Ok(kek),
+    }
+}
+
+// Secret supplied to unlock a vault. Only slots of the matching type are tried.
+// `MasterPasswordWithKeyfile` is the password plus keyfile contents, for
+// vaults whose header has `keyfile_required` set.
+#[derive(Clone, Copy)]
+pub enum Credential<'a> {
+    MasterPassword(&'a [u8]),
+    MasterPasswordWithKeyfile(&'a [u8], &'a [u8]),
+    RecoveryKey(&'a [u8]),
+    Keyfile(&'a [u8]),
+}
+
+impl<'a> Credential<'a> {
+    // Master password credential, with the keyfile contents if one is in use
+    pub fn password(password: &'a [u8], keyfile: Option<&'a [u8]>) -> Self {
+        match keyfile {
+            Some(keyfile) => Credential::MasterPasswordWithKeyfile(password, keyfile),
+            None => Credential::MasterPassword(password),
+        }
+    }
+
+    pub fn slot_type(&self) -> SlotType {
+        match self {
+            Credential::MasterPassword(_) | Credential::MasterPasswordWithKeyfile(..) => SlotType::MasterPassword,
+            Credential::RecoveryKey(_) => SlotType::RecoveryKey,
+            Credential::Keyfile(_) => SlotType::Keyfile,
+        }
+    }
+
+    pub fn secret(&self) -> &[u8] {
+        match self {
+            Credential::MasterPassword(s)
+            | Credential::MasterPasswordWithKeyfile(s, _)
+            | Credential::RecoveryKey(s)
+            | Credential::Keyfile(s) => s,
+        }
+    }
+
+    // Second factor mixed into the KEK, if any
+    pub fn keyfile(&self) -> Option<&[u8]> {
+        match self {
+            Credential::MasterPasswordWithKeyfile(_, keyfile) => Some(keyfile),
+            _ => None,
+        }
+    }
+}
+
+impl VaultStore {
+    pub fn list_key_slots(&self) -> &[KeySlot] {
+        &self.header.key_slots
+    }
+
+    // Try every slot of the credential's type; the first one that unwraps wins.
+    // Signed headers must also have been signed with the key this DEK derives.
+    pub fn unlock_dek(&self, credential: Credential) -> std::io::Result<Zeroizing<[u8; 32]>> {
+        self.unlock_slot(credential).map(|(_, dek)| dek)
+    }
+
+    // Re-seal the slot that `credential` opens if its KDF costs are below
+    // `policy`, replacing the header atomically. Returns what was upgraded,
+    // or None if the slot already meets the policy.
+    pub fn upgrade_kdf(&mut self, credential: Credential, policy: &KdfPolicy) -> std::io::Result<Option<KdfUpgrade>> {
+        let (slot, dek) = self.unlock_slot(credential)?;
+        self.upgrade_slot_kdf(slot, credential, &dek, policy)
+    }
+
+    // Enrol another credential for the same DEK. Returns the new slot's id.
+    pub fn add_key_slot(&mut self, dek: &[u8; 32], credential: Credential, params: ArgonParams) -> std::io::Result<u32> {
+        self.check_keyfile_policy(credential)?;
+        let mut slot = KeySlot::seal(0, credential, params, self.header.cipher_suite, dek)?;
+        self.update_header(dek, |header| {
+            // The id is not sealed into the slot, so it can be picked against
+            // the header as it is on disk
+            slot.id = next_slot_id(header);
+            header.key_slots.push(slot);
+            Ok(header.key_slots[header.key_slots.len() - 1].id)
+        })
+    }
+
+    // Enrol a slot for recovery key `key` and drop every older recovery key
+    // slot in the same header write. Returns the new slot's id.
+    pub(crate) fn replace_recovery_key(&mut self, dek: &[u8; 32], key: &[u8]) -> std::io::Result<u32> {
+        let credential = Credential::RecoveryKey(key);
+        let mut slot = KeySlot::seal(0, credential, recovery_key_params(), self.header.cipher_suite, dek)?;
+        self.update_header(dek, |header| {
+            slot.id = next_slot_id(header);
+            header.key_slots.retain(|s| s.slot_type != SlotType::RecoveryKey);
+            header.key_slots.push(slot);
+            Ok(header.key_slots[header.key_slots.len() - 1].id)
+        })
+    }
+
+    // The last slot cannot be removed, since that would make the vault unopenable
+    pub fn remove_key_slot(&mut self, dek: &[u8; 32], id: u32) -> std::io::Result<()> {
+        self.update_header(dek, |header| {
+            let before = header.key_slots.len();
+            header.key_slots.retain(|s| s.id != id);
+            if header.key_slots.len() == before {
+                return Err(std::io::Error::new(std::io::ErrorKind::NotFound, "no such key slot"));
+            }
+            if header.key_slots.is_empty() {
+                return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "cannot remove the last key slot"));
+            }
+            Ok(())
+        })
+    }
+
+    // `upgrade_kdf` for a slot already opened with `credential`
+    pub(crate) fn upgrade_slot_kdf(
+        &mut self,
+        slot: usize,
+        credential: Credential,
+        dek: &[u8; 32],
+        policy: &KdfPolicy,
+    ) -> std::io::Result<Option<KdfUpgrade>> {
+        let old = &self.header.key_slots[slot];
+        let from = ArgonParams::from(old.kdf_params.clone());
+        if policy.allows(&from) {
+            return Ok(None);
+        }
+        let to = policy.raise(from);
+        let upgrade = KdfUpgrade { slot_id: old.id, slot_type: old.slot_type, from: from.into(), to: to.into() };
+        let sealed = KeySlot::seal(old.id, credential, to, self.header.cipher_suite, dek)?;
+        let opened = old.clone();
+        self.update_header(dek, |header| {
+            let slot = find_opened_slot(header, &opened)?;
+            header.key_slots[slot] = sealed;
+            Ok(())
+        })?;
+        Ok(Some(upgrade))
+    }
+
+    // `open_slot`, then check that the header was signed with the DEK. Signed
+    // formats are checked even when they are due for an upgrade, so a header
+    // labelled as an older signed version cannot skip the check.
+    pub(crate) fn unlock_slot(&self, credential: Credential) -> std::io::Result<(usize, Zeroizing<[u8; 32]>)> {
+        let (slot, dek) = self.open_slot(credential)?;
+        if self.header.is_signed() {
+            self.header.verify_signer(&dek)?;
+        }
+        Ok((slot, dek))
+    }
+
+    // Index of the slot that accepted `credential`, and the DEK it unwrapped.
+    // A slot that cannot be tried at all, such as one with bad KDF params, is
+    // skipped so it cannot lock out the others. Its error is only returned if
+    // no other slot got as far as rejecting the credential.
+    pub(crate) fn open_slot(&self, credential: Credential) -> std::io::Result<(usize, Zeroizing<[u8; 32]>)> {
+        self.check_keyfile_policy(credential)?;
+        let wanted = credential.slot_type();
+        let mut failure = None;
+        for (i, slot) in self.header.key_slots.iter().enumerate() {
+            if slot.slot_type != wanted {
+                continue;
+            }
+            match slot.open(credential, self.header.cipher_suite) {
+                Ok(dek) => return Ok((i, dek)),
+                Err(CryptoError::AuthenticationFailed) => failure = Some(CryptoError::AuthenticationFailed),
+                Err(e) => {
+                    failure.get_or_insert(e);
+                }
+            }
+        }
+        match failure {
+            Some(e) => Err(to_io_err(e)),
+            None => Err(std::io::Error::new(std::io::ErrorKind::NotFound, "no key slot for this credential type")),
+        }
+    }
+
+    // Password slots need a keyfile exactly when the header says so
+    pub(crate) fn check_keyfile_policy(&self, credential: Credential) -> std::io::Result<()> {
+        if credential.slot_type() != SlotType::MasterPassword {
+            return Ok(());
+        }
+        match (self.header.keyfile_required, credential.keyfile().is_some()) {
+            (true, false) => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "this vault requires a keyfile")),
+            (false, true) => Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "this vault does not use a keyfile")),
+            _ => Ok(()),
+        }
+    }
+}
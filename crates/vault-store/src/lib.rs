@@ -1,20 +1,168 @@
-use core_crypto::{aead_decrypt_aes_gcm, aead_encrypt_aes_gcm, derive_item_key, ArgonParams};
+use core_crypto::{
+    derive_header_signing_key, generate_recovery_key, header_verify_key, parse_recovery_key, sign_header, verify_header,
+    ArgonParams, CipherSuite, StreamNonce,
+};
 use serde::{Deserialize, Serialize};
 use std::fs::{File, OpenOptions};
-use std::io::{Read, Seek, SeekFrom, Write};
+use std::collections::HashMap;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::Path;
+use std::time::Duration;
 use uuid::Uuid;
-use std::collections::HashMap;
+use zeroize::Zeroizing;
+
+mod attachment;
+mod index;
+mod keyslot;
+mod lock;
+mod migrate;
+mod record;
+mod search;
+
+pub use attachment::Attachment;
+use attachment::encode_attachment;
+use keyslot::find_opened_slot;
+pub use keyslot::{recovery_key_params, Credential, KdfPolicy, KdfUpgrade, KeySlot, SlotType};
+pub use lock::DEFAULT_LOCK_TIMEOUT;
+use lock::{LockMode, VaultLock};
+pub use migrate::{MigrationReport, MigrationStep};
+use migrate::VaultHeaderV6;
+pub use record::{IntegrityFailure, IntegrityIssue, IntegrityReport};
+use index::EntryIndex;
+use record::{
+    encode_chunk, encode_record, latest_live_entries, live_ids, parse_trailer, retained_records, tombstone_payload,
+    trashed_entries, Chain, ChunkReader, Format, Record, RecordKind, RecordRef, PENDING_LEN, TRAILER_LEN,
+};
+
+pub const MAGIC: [u8; 5] = *b"SVLT1";
+// What each version changed is listed in `migrate::STEPS`
+pub const FORMAT_VERSION: u16 = 9;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VaultHeader {
-    pub magic: [u8; 5],       // "SVLT1"
-    pub version: u16,         // 1
-    pub kdf_params: ArgonParamsOnDisk,
-    pub salt_kek: [u8; 32],
-    pub wrapped_dek: Vec<u8>, // nonce || ct
+    pub magic: [u8; 5],       // MAGIC
+    pub version: u16,         // FORMAT_VERSION
+    pub key_slots: Vec<KeySlot>,
+    // Master password slots mix a keyfile into their KEK
+    pub keyfile_required: bool,
+    // AEAD for the key slots and every record
+    pub cipher_suite: CipherSuite,
+    // Public half of the DEK-derived header signing key
+    pub verify_key: [u8; 32],
+    // Signature over the header serialized with this field empty
+    pub signature: Vec<u8>,
+}
+
+impl VaultHeader {
+    // Unsigned header; `VaultStore::create` signs it with the DEK. The key
+    // slots must have been sealed with `cipher_suite`.
+    pub fn new(key_slots: Vec<KeySlot>, keyfile_required: bool, cipher_suite: CipherSuite) -> Self {
+        Self {
+            magic: MAGIC,
+            version: FORMAT_VERSION,
+            key_slots,
+            keyfile_required,
+            cipher_suite,
+            verify_key: [0u8; 32],
+            signature: Vec::new(),
+        }
+    }
+
+    pub fn sign(&mut self, dek: &[u8; 32]) -> std::io::Result<()> {
+        let signing_key = Zeroizing::new(derive_header_signing_key(dek).map_err(to_io_err)?);
+        self.verify_key = header_verify_key(&signing_key);
+        let signature = sign_header(&signing_key, &self.signed_bytes()?);
+        self.signature = signature.to_vec();
+        Ok(())
+    }
+
+    // Headers are signed from v6 on
+    pub fn is_signed(&self) -> bool {
+        self.version >= 6
+    }
+
+    // Checks the signature against the stored verify key. This catches
+    // corruption, but anyone can re-sign with their own key; `verify_signer`
+    // is the check that needs the DEK.
+    pub fn verify_signature(&self) -> std::io::Result<()> {
+        let sig: [u8; 64] = self.signature.as_slice().try_into().map_err(|_| to_io_err(VaultError::HeaderTampered))?;
+        verify_header(&self.verify_key, &self.signed_bytes()?, &sig).map_err(|_| to_io_err(VaultError::HeaderTampered))
+    }
+
+    // Checks that the header was signed with the key derived from `dek`
+    pub fn verify_signer(&self, dek: &[u8; 32]) -> std::io::Result<()> {
+        let signing_key = Zeroizing::new(derive_header_signing_key(dek).map_err(to_io_err)?);
+        if header_verify_key(&signing_key) != self.verify_key {
+            return Err(to_io_err(VaultError::HeaderTampered));
+        }
+        self.verify_signature()
+    }
+
+    // Headers are signed in the layout they were written in, so v6-v8
+    // signatures still verify until the vault is upgraded
+    fn signed_bytes(&self) -> std::io::Result<Vec<u8>> {
+        let mut unsigned = self.clone();
+        unsigned.signature = Vec::new();
+        if self.version < 9 {
+            return postcard::to_stdvec(&VaultHeaderV6::from(unsigned)).map_err(to_io_err);
+        }
+        postcard::to_stdvec(&unsigned).map_err(to_io_err)
+    }
+}
+
+// Vault-level failures that callers may want to tell apart. They travel inside
+// `std::io::Error`; use `VaultError::of` to get them back out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VaultError {
+    HeaderTampered,
+    // A record failed to verify, or the log does not match its trailer
+    Integrity(IntegrityFailure),
+    // The last append was interrupted and left an incomplete record; see
+    // `UnlockedVault::unlock_and_recover`
+    TornWrite(TornWriteReport),
+    // Another process held the vault lock for longer than the lock timeout
+    VaultLocked,
+    // The vault is in a format from before headers were signed, so nothing
+    // shows whether its header was changed; see `UnlockedVault::unlock_and_migrate`
+    UnsignedHeader { version: u16 },
+    // Another process changed the vault in a way this session cannot follow,
+    // such as rotating the DEK; unlocking it again picks up the change
+    VaultChanged,
+}
+
+impl VaultError {
+    pub fn of(e: &std::io::Error) -> Option<VaultError> {
+        e.get_ref().and_then(|inner| inner.downcast_ref::<VaultError>()).copied()
+    }
 }
 
+impl std::fmt::Display for VaultError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VaultError::HeaderTampered => f.write_str("vault header signature is invalid - the header has been modified"),
+            VaultError::Integrity(failure) => {
+                write!(f, "vault integrity check failed at offset {}: {}", failure.offset, failure.issue)
+            }
+            VaultError::TornWrite(torn) => write!(
+                f,
+                "the last write to the vault was interrupted and left an incomplete record at offset {}; \
+                 recovering drops the last {} bytes and keeps the {} records before them",
+                torn.offset, torn.bytes_dropped, torn.records_kept
+            ),
+            VaultError::VaultLocked => f.write_str("the vault is in use by another process"),
+            VaultError::UnsignedHeader { version } => write!(
+                f,
+                "vault format v{} has no header signature, so changes to its header cannot be detected; \
+                 upgrading it signs the header as it is",
+                version
+            ),
+            VaultError::VaultChanged => f.write_str("the vault was changed by another process; unlock it again"),
+        }
+    }
+}
+
+impl std::error::Error for VaultError {}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ArgonParamsOnDisk {
     pub mem_kib: u32,
@@ -34,102 +182,92 @@ impl From<ArgonParamsOnDisk> for ArgonParams {
     }
 }
 
+// Reads take a shared lock on the vault and writes an exclusive one, so
+// several processes can use the same file without interleaving records
 pub struct VaultStore {
     pub header: VaultHeader,
     pub path: String,
+    lock_timeout: Duration,
+    retention: RetentionPolicy,
 }
 
 impl VaultStore {
-    pub fn create<P: AsRef<Path>>(path: P, header: VaultHeader) -> std::io::Result<()> {
+    // `dek` must be the key wrapped by the header's slots; it signs the header
+    pub fn create<P: AsRef<Path>>(path: P, mut header: VaultHeader, dek: &[u8; 32]) -> std::io::Result<()> {
         let path_ref = path.as_ref();
+        header.sign(dek)?;
+        let _lock = VaultLock::acquire(&path_ref.to_string_lossy(), LockMode::Exclusive, DEFAULT_LOCK_TIMEOUT)?;
         let mut f = OpenOptions::new().create_new(true).write(true).open(path_ref)?;
-        let header_bytes = postcard::to_stdvec(&header).map_err(to_io_err)?;
-        // Write length (u32 LE) then header
-        let len = header_bytes.len() as u32;
-        f.write_all(&len.to_le_bytes())?;
-        f.write_all(&header_bytes)?;
-        f.flush()?;
-        Ok(())
+        write_header(&mut f, &header)?;
+        f.write_all(&Chain::new(dek)?.trailer())?;
+        f.sync_all()?;
+        sync_parent_dir(path_ref)
     }
 
     pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Self::open_with_lock_timeout(path, DEFAULT_LOCK_TIMEOUT)
+    }
+
+    // `lock_timeout` is how long every operation on the returned store waits
+    // for another process to release the vault before failing with
+    // `VaultError::VaultLocked`
+    pub fn open_with_lock_timeout<P: AsRef<Path>>(path: P, lock_timeout: Duration) -> std::io::Result<Self> {
         let path_ref = path.as_ref();
-        let mut f = File::open(path_ref)?;
-        let mut len_bytes = [0u8; 4];
-        f.read_exact(&mut len_bytes)?;
-        let len = u32::from_le_bytes(len_bytes) as usize;
-        let mut buf = vec![0u8; len];
-        f.read_exact(&mut buf)?;
-        let header: VaultHeader = postcard::from_bytes(&buf).map_err(to_io_err)?;
-        if header.magic != *b"SVLT1" || header.version != 1 {
-            return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid vault header"));
-        }
-        Ok(Self { header, path: path_ref.to_string_lossy().into_owned() })
+        let path = path_ref.to_string_lossy().into_owned();
+        let _lock = VaultLock::acquire(&path, LockMode::Shared, lock_timeout)?;
+        let header = read_header(&path)?;
+        Ok(Self { header, path, lock_timeout, retention: RetentionPolicy::default() })
+    }
+
+    pub fn set_lock_timeout(&mut self, timeout: Duration) {
+        self.lock_timeout = timeout;
+    }
+
+    // What `compact` keeps besides the latest version of each entry
+    pub fn set_retention(&mut self, retention: RetentionPolicy) {
+        self.retention = retention;
+    }
+
+    // Vaults written by an older format can be read but must be upgraded before
+    // anything is appended to them
+    pub fn needs_upgrade(&self) -> bool {
+        self.header.version < FORMAT_VERSION
     }
 
-    // Append-only record write (encrypted). Format: len | id(16) | nonce(12) | ct
+    // Append-only record write (encrypted), see `record::encode_record`
     pub fn write_entry(&self, dek: &[u8; 32], entry: &VaultEntry) -> std::io::Result<()> {
-        let mut f = OpenOptions::new().append(true).open(&self.path)?;
-        let ser = postcard::to_stdvec(entry).map_err(to_io_err)?;
-        let id_bytes = entry.id.as_bytes();
-        let item_key = derive_item_key(dek, &id_bytes[..16].try_into().expect("uuid slice"));
-        let mut nonce = [0u8; 12];
-        getrandom::getrandom(&mut nonce).map_err(to_io_err)?;
-        let aad = id_bytes;
-        let ct = aead_encrypt_aes_gcm(&item_key, &nonce, &ser, aad);
-        let total_len = 16 + 12 + ct.len();
-        let len_u32 = total_len as u32;
-        f.write_all(&len_u32.to_le_bytes())?;
-        f.write_all(id_bytes)?;
-        f.write_all(&nonce)?;
-        f.write_all(&ct)?;
-        f.flush()?;
-        Ok(())
+        self.append_record(dek, RecordKind::Entry, entry.id, &encode_entry(entry)?).map(|_| ())
     }
 
+    // All stored versions of every entry that has not been deleted, in log order
     pub fn read_all_entries(&self, dek: &[u8; 32]) -> std::io::Result<Vec<VaultEntry>> {
-        let mut f = File::open(&self.path)?;
-        // skip header
-        let mut len_bytes = [0u8; 4];
-        f.read_exact(&mut len_bytes)?;
-        let len = u32::from_le_bytes(len_bytes) as u64;
-        f.seek(SeekFrom::Start(4 + len))?;
-        let mut out = Vec::new();
-        loop {
-            let mut lbuf = [0u8; 4];
-            match f.read_exact(&mut lbuf) {
-                Ok(()) => {}
-                Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => break,
-                Err(e) => return Err(e),
-            }
-            let clen = u32::from_le_bytes(lbuf) as usize;
-            if clen < 16 + 12 + 16 { break; }
-            let mut id_buf = [0u8; 16];
-            f.read_exact(&mut id_buf)?;
-            let mut n = [0u8; 12];
-            f.read_exact(&mut n)?;
-            let ct_len = clen - 16 - 12;
-            let mut ct = vec![0u8; ct_len];
-            f.read_exact(&mut ct)?;
-            let item_key = derive_item_key(dek, &id_buf);
-            let aad = &id_buf;
-            let pt = aead_decrypt_aes_gcm(&item_key, &ct, &n, aad);
-            let entry: VaultEntry = postcard::from_bytes(&pt).map_err(to_io_err)?;
-            out.push(entry);
-        }
-        Ok(out)
+        let _lock = self.lock(LockMode::Shared)?;
+        let records = self.read_records(dek)?;
+        let live = live_ids(&records);
+        Ok(records
+            .into_iter()
+            .filter_map(|r| match r {
+                Record::Entry(e) if live.contains_key(&e.id) => Some(e),
+                _ => None,
+            })
+            .collect())
     }
 
     pub fn list_entries(&self, dek: &[u8; 32]) -> std::io::Result<Vec<(Uuid, String)>> {
-        let entries = self.read_all_entries(dek)?;
+        let _lock = self.lock(LockMode::Shared)?;
+        let entries = latest_live_entries(self.read_records(dek)?);
         Ok(entries.into_iter().map(|e| (e.id, e.title)).collect())
     }
 
-    // Get the latest version of an entry by ID (handles updates by taking the last occurrence)
+    // Get the latest version of an entry by ID, or None if it was deleted
     pub fn get_entry(&self, dek: &[u8; 32], id: Uuid) -> std::io::Result<Option<VaultEntry>> {
-        let entries = self.read_all_entries(dek)?;
-        // Find the last occurrence of this ID (most recent version)
-        Ok(entries.into_iter().rev().find(|e| e.id == id))
+        let _lock = self.lock(LockMode::Shared)?;
+        let records = self.read_records(dek)?;
+        // The most recent record for this ID decides whether it is live
+        Ok(records.into_iter().rev().find(|r| r.id() == id).and_then(|r| match r {
+            Record::Entry(e) => Some(e),
+            _ => None,
+        }))
     }
 
     // Update an entry by writing a new version with the same ID
@@ -138,34 +276,887 @@ impl VaultStore {
         self.write_entry(dek, &entry)
     }
 
-    // Mark an entry as deleted by writing a special "deleted" entry
+    // Every stored version of `id`, oldest first, including ones written before
+    // it was deleted. Each version's `modified_at` says when it was saved.
+    pub fn entry_history(&self, dek: &[u8; 32], id: Uuid) -> std::io::Result<Vec<VaultEntry>> {
+        let _lock = self.lock(LockMode::Shared)?;
+        Ok(self
+            .read_records(dek)?
+            .into_iter()
+            .filter_map(|r| match r {
+                Record::Entry(e) if e.id == id => Some(e),
+                _ => None,
+            })
+            .collect())
+    }
+
+    // Write version `n` of `entry_history(dek, id)` again as the latest
+    // version, and return it. Newer versions stay in the history.
+    pub fn restore_version(&self, dek: &[u8; 32], id: Uuid, n: usize) -> std::io::Result<VaultEntry> {
+        let entry = self.version_to_restore(dek, id, n)?;
+        self.write_entry(dek, &entry)?;
+        Ok(entry)
+    }
+
+    fn version_to_restore(&self, dek: &[u8; 32], id: Uuid, n: usize) -> std::io::Result<VaultEntry> {
+        let mut entry = self
+            .entry_history(dek, id)?
+            .into_iter()
+            .nth(n)
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "no such entry version"))?;
+        entry.touch();
+        Ok(entry)
+    }
+
+    // Move an entry to the trash by appending a tombstone record for its ID.
+    // It can be restored until it is purged by `empty_trash` or by compaction.
     pub fn delete_entry(&self, dek: &[u8; 32], id: Uuid) -> std::io::Result<()> {
-        let deleted_entry = VaultEntry {
-            id,
-            title: "".to_string(),
-            username: "".to_string(),
-            password: "".to_string(),
-        };
-        self.write_entry(dek, &deleted_entry)
+        self.append_record(dek, RecordKind::Tombstone, id, &tombstone_payload(unix_now())).map(|_| ())
+    }
+
+    // Deleted entries that have not been purged yet, most recently deleted first
+    pub fn list_trash(&self, dek: &[u8; 32]) -> std::io::Result<Vec<TrashedEntry>> {
+        let _lock = self.lock(LockMode::Shared)?;
+        Ok(trashed_entries(self.read_records(dek)?)
+            .into_iter()
+            .map(|(e, deleted_at)| TrashedEntry { id: e.id, title: e.title, deleted_at })
+            .collect())
+    }
+
+    // Write the last version of a trashed entry again, making it live
+    pub fn restore_from_trash(&self, dek: &[u8; 32], id: Uuid) -> std::io::Result<VaultEntry> {
+        let entry = self.entry_to_untrash(dek, id)?;
+        self.write_entry(dek, &entry)?;
+        Ok(entry)
+    }
+
+    fn entry_to_untrash(&self, dek: &[u8; 32], id: Uuid) -> std::io::Result<VaultEntry> {
+        let _lock = self.lock(LockMode::Shared)?;
+        trashed_entries(self.read_records(dek)?)
+            .into_iter()
+            .find(|(e, _)| e.id == id)
+            .map(|(mut e, _)| {
+                e.touch();
+                e
+            })
+            .ok_or_else(|| std::io::Error::new(std::io::ErrorKind::NotFound, "entry is not in the trash"))
+    }
+
+    // Purge every trashed entry, with all its versions, right away. Live
+    // entries and their history are left as they are.
+    pub fn empty_trash(&self, dek: &[u8; 32]) -> std::io::Result<CompactionReport> {
+        self.compact_with(dek, usize::MAX, u64::MAX)
     }
 
     // Get all active (non-deleted) entries
     pub fn list_active_entries(&self, dek: &[u8; 32]) -> std::io::Result<Vec<(Uuid, String)>> {
-        let entries = self.read_all_entries(dek)?;
-        let mut active_entries = HashMap::new();
-        
-        // Process entries in order, keeping only the latest version of each ID
-        for entry in entries {
-            if entry.title.is_empty() && entry.username.is_empty() && entry.password.is_empty() {
-                // This is a deletion marker, remove from active entries
-                active_entries.remove(&entry.id);
-            } else {
-                // This is a regular entry, keep the latest version
-                active_entries.insert(entry.id, entry.title);
+        self.list_entries(dek)
+    }
+
+    // Rewrite the log so it holds only live and trashed entries, each with its
+    // latest version and as many older ones as the retention policy allows.
+    // Entries that have been in the trash longer than the policy allows are
+    // purged. The new log is built in a sibling temp file, fsynced and renamed
+    // over the original, so a crash leaves either the old or the new vault intact.
+    pub fn compact(&self, dek: &[u8; 32]) -> std::io::Result<CompactionReport> {
+        self.compact_with(dek, self.retention.history_versions, self.purge_before())
+    }
+
+    // Trashed entries deleted before this are purged under the retention policy.
+    // With no days to keep them, the whole trash goes, even what was deleted
+    // this very second.
+    fn purge_before(&self) -> u64 {
+        match self.retention.trash_days {
+            0 => u64::MAX,
+            days => unix_now().saturating_sub(days.saturating_mul(24 * 60 * 60)),
+        }
+    }
+
+    // `compact` with `history` older versions per entry, purging the entries
+    // trashed before `purge_before` (Unix seconds) rather than going by the
+    // retention policy
+    pub fn compact_with(&self, dek: &[u8; 32], history: usize, purge_before: u64) -> std::io::Result<CompactionReport> {
+        self.ensure_current()?;
+        let _lock = self.lock(LockMode::Exclusive)?;
+        // Copied over as it is on disk, so it has to be signed with `dek`
+        let header = self.current_header(dek)?;
+        let bytes_before = std::fs::metadata(&self.path)?.len();
+        let keep = retained_records(self.read_records(dek)?, history, purge_before);
+        self.rewrite(dek, dek, &header, &keep, &mut |_, _| {})?;
+
+        let bytes_after = std::fs::metadata(&self.path)?.len();
+        Ok(CompactionReport {
+            entries_kept: live_ids(&keep).len(),
+            versions_kept: keep.iter().filter(|r| matches!(r, Record::Entry(_))).count(),
+            bytes_reclaimed: bytes_before.saturating_sub(bytes_after),
+        })
+    }
+
+    // Re-encode every record of an older-format vault in the current format.
+    // History and deletions are preserved; only the on-disk framing changes.
+    pub fn upgrade(&mut self, dek: &[u8; 32]) -> std::io::Result<()> {
+        self.migrate(dek, false).map(|_| ())
+    }
+
+    // Re-wrap the DEK in the password slot that `old` opens, under a KEK derived
+    // from `new` with a fresh salt. Entry records are encrypted under the DEK,
+    // so they are copied over unchanged. Both credentials must be password
+    // credentials, with a keyfile if the vault requires one. Without
+    // `new_params` the slot keeps its KDF costs.
+    pub fn change_master_password(
+        &mut self,
+        old: Credential,
+        new: Credential,
+        new_params: Option<ArgonParams>,
+    ) -> std::io::Result<()> {
+        if old.slot_type() != SlotType::MasterPassword || new.slot_type() != SlotType::MasterPassword {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "expected master password credentials"));
+        }
+        self.check_keyfile_policy(new)?;
+        let (slot, dek) = self.unlock_slot(old)?;
+        let opened = self.header.key_slots[slot].clone();
+        let params = new_params.unwrap_or_else(|| ArgonParams::from(opened.kdf_params.clone()));
+        let sealed = KeySlot::seal(opened.id, new, params, self.header.cipher_suite, &dek)?;
+        self.update_header(&dek, |header| {
+            let slot = find_opened_slot(header, &opened)?;
+            header.key_slots[slot] = sealed;
+            Ok(())
+        })
+    }
+
+    // Generate a fresh DEK and re-encrypt under it everything `compact` would
+    // keep: live and trashed entries with their retained history, and their
+    // attachments. Only the slot that `credential` opens is re-wrapped; every
+    // other slot is removed and has to be enrolled again. Returns the new DEK.
+    pub fn rotate_dek(
+        &mut self,
+        credential: Credential,
+        mut progress: impl FnMut(usize, usize),
+    ) -> std::io::Result<Zeroizing<[u8; 32]>> {
+        self.ensure_current()?;
+        let (slot, old_dek) = self.unlock_slot(credential)?;
+        let opened = self.header.key_slots[slot].clone();
+        let _lock = self.lock(LockMode::Exclusive)?;
+        let mut header = self.current_header(&old_dek)?;
+        find_opened_slot(&header, &opened)?;
+        let history = self.retention.history_versions;
+        let records = retained_records(self.read_records(&old_dek)?, history, self.purge_before());
+
+        let mut new_dek = Zeroizing::new([0u8; 32]);
+        getrandom::getrandom(new_dek.as_mut()).map_err(to_io_err)?;
+        let params = ArgonParams::from(opened.kdf_params.clone());
+        header.key_slots = vec![KeySlot::seal(opened.id, credential, params, header.cipher_suite, &new_dek)?];
+        header.sign(&new_dek)?;
+
+        self.rewrite(&old_dek, &new_dek, &header, &records, &mut progress)?;
+        self.header = header;
+        Ok(new_dek)
+    }
+
+    // Apply `edit` to the header, sign it with `dek` and write it back, all
+    // under the exclusive lock. The header is re-read from disk first, so a
+    // change another process made since this store read it is kept rather
+    // than overwritten, and it must already be signed with `dek`, so a header
+    // someone re-signed with their own key is never taken over.
+    fn update_header<T>(
+        &mut self,
+        dek: &[u8; 32],
+        edit: impl FnOnce(&mut VaultHeader) -> std::io::Result<T>,
+    ) -> std::io::Result<T> {
+        self.ensure_current()?;
+        let _lock = self.lock(LockMode::Exclusive)?;
+        let mut header = self.current_header(dek)?;
+        let edited = edit(&mut header)?;
+        header.sign(dek)?;
+        self.rewrite_header(&header)?;
+        self.header = header;
+        Ok(edited)
+    }
+
+    // The header as it is on disk now, which another process may have
+    // rewritten since this store read it. It must be signed with `dek`: if it
+    // carries another key than the one this store read, the vault most likely
+    // had its DEK rotated and this session is out of date. The caller must
+    // hold the lock.
+    fn current_header(&self, dek: &[u8; 32]) -> std::io::Result<VaultHeader> {
+        let header = read_header(&self.path)?;
+        match header.verify_signer(dek) {
+            Ok(()) => Ok(header),
+            Err(_) if header.verify_key != self.header.verify_key => Err(to_io_err(VaultError::VaultChanged)),
+            Err(e) => Err(e),
+        }
+    }
+
+    // Replace the header and copy the record bytes after it verbatim. The
+    // header is length-prefixed, so this goes through a temp file and rename.
+    // The caller must hold the exclusive lock.
+    fn rewrite_header(&self, header: &VaultHeader) -> std::io::Result<()> {
+        let mut src = File::open(&self.path)?;
+        let mut len_bytes = [0u8; 4];
+        src.read_exact(&mut len_bytes)?;
+        let len = u32::from_le_bytes(len_bytes) as u64;
+        src.seek(SeekFrom::Start(4 + len))?;
+
+        let tmp_path = temp_path(&self.path);
+        let result = (|| {
+            let mut f = OpenOptions::new().create(true).truncate(true).write(true).open(&tmp_path)?;
+            write_header(&mut f, header)?;
+            std::io::copy(&mut src, &mut f)?;
+            f.sync_all()
+        })();
+        if let Err(e) = result {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+        replace_file(&tmp_path, Path::new(&self.path))
+    }
+
+    // Taken once per operation: a second lock on the same vault from this
+    // process would wait on the first. `read_records` and `rewrite` assume
+    // their caller holds it.
+    fn lock(&self, mode: LockMode) -> std::io::Result<VaultLock> {
+        VaultLock::acquire(&self.path, mode, self.lock_timeout)
+    }
+
+    fn ensure_current(&self) -> std::io::Result<()> {
+        if self.needs_upgrade() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("vault format v{} must be upgraded before it can be modified", self.header.version),
+            ));
+        }
+        Ok(())
+    }
+
+    // Returns where the record went and the new chain head
+    fn append_record(
+        &self,
+        dek: &[u8; 32],
+        kind: RecordKind,
+        id: Uuid,
+        payload: &[u8],
+    ) -> std::io::Result<(RecordRef, [u8; 32])> {
+        self.ensure_current()?;
+        let _lock = self.lock(LockMode::Exclusive)?;
+        self.append_record_locked(dek, None, kind, id, payload)
+    }
+
+    // `append_record` for a caller that already holds the exclusive lock; see
+    // `begin_append` for `expected_head`
+    fn append_record_locked(
+        &self,
+        dek: &[u8; 32],
+        expected_head: Option<[u8; 32]>,
+        kind: RecordKind,
+        id: Uuid,
+        payload: &[u8],
+    ) -> std::io::Result<(RecordRef, [u8; 32])> {
+        let mut appender = self.begin_append(dek, expected_head)?;
+        let at = appender.push(encode_record(dek, self.header.cipher_suite, kind, id, payload, &appender.head())?)?;
+        Ok((at, appender.finish()?))
+    }
+
+    // Records go where the trailer was, followed by a new trailer carrying the
+    // advanced head. Fails with `VaultError::VaultChanged` if the header on
+    // disk is no longer signed with `dek`, since records sealed under a DEK
+    // another process rotated away would be unreadable, or if the trailer's
+    // head is not `expected_head`, the head the caller last saw. The head
+    // itself is trusted here; `read_records` checks it. The caller must hold
+    // the exclusive lock.
+    fn begin_append(&self, dek: &[u8; 32], expected_head: Option<[u8; 32]>) -> std::io::Result<Appender> {
+        self.current_header(dek)?;
+        let mut f = OpenOptions::new().read(true).write(true).open(&self.path)?;
+        let mut len_bytes = [0u8; 4];
+        f.read_exact(&mut len_bytes)?;
+        let data_start = 4 + u32::from_le_bytes(len_bytes) as u64;
+        let trailer_at = f.metadata()?.len().checked_sub(TRAILER_LEN).ok_or_else(|| self.torn_write_error(dek))?;
+        f.seek(SeekFrom::Start(trailer_at))?;
+        let mut trailer = [0u8; TRAILER_LEN as usize];
+        f.read_exact(&mut trailer)?;
+        let head = parse_trailer(&trailer).ok_or_else(|| self.torn_write_error(dek))?;
+        if expected_head.is_some_and(|expected| expected != head) {
+            return Err(to_io_err(VaultError::VaultChanged));
+        }
+        let chain = Chain::resume(dek, head)?;
+        f.seek(SeekFrom::Start(trailer_at))?;
+        Ok(Appender { w: BufWriter::new(f), data_start, start: trailer_at, offset: trailer_at, chain, first_len: None })
+    }
+
+    fn read_records(&self, dek: &[u8; 32]) -> std::io::Result<Vec<Record>> {
+        let scan = record::scan(&self.path, Format::of(&self.header), dek)?;
+        match scan.failure {
+            Some(failure) => Err(to_io_err(VaultError::Integrity(failure))),
+            None => Ok(scan.records),
+        }
+    }
+
+    // Offset of an incomplete record left at the end of the log by a crash
+    // during an append, if there is one
+    pub fn find_torn_write(&self) -> std::io::Result<Option<u64>> {
+        let _lock = self.lock(LockMode::Shared)?;
+        record::find_torn_write(&self.path, Format::of(&self.header))
+    }
+
+    // What `recover_torn_write` would drop, without changing anything. Unlike
+    // `find_torn_write` this decrypts the log, to count the records kept.
+    pub fn check_torn_write(&self, dek: &[u8; 32]) -> std::io::Result<Option<TornWriteReport>> {
+        let _lock = self.lock(LockMode::Shared)?;
+        Ok(self.scan_torn_write(dek)?.map(|(torn, _)| torn))
+    }
+
+    // Truncate the log at an interrupted append and, in chained formats, write
+    // a trailer for the records before it. Every remaining record must verify.
+    // Returns what was dropped, or None if the log was complete.
+    pub fn recover_torn_write(&self, dek: &[u8; 32]) -> std::io::Result<Option<TornWriteReport>> {
+        let _lock = self.lock(LockMode::Exclusive)?;
+        let (torn, head) = match self.scan_torn_write(dek)? {
+            Some(found) => found,
+            None => return Ok(None),
+        };
+        let mut f = OpenOptions::new().write(true).open(&self.path)?;
+        // The trailer goes in before the tail is cut, since a log cut at a
+        // record boundary reads as truncated. A crash between the two leaves
+        // the trailer's magic where a length prefix should be, which is itself
+        // a torn write and recovered the same way.
+        let mut len = torn.offset;
+        if record::has_chain(self.header.version) {
+            f.seek(SeekFrom::Start(torn.offset))?;
+            f.write_all(&Chain::resume(dek, head)?.trailer())?;
+            f.sync_all()?;
+            len += TRAILER_LEN;
+        }
+        f.set_len(len)?;
+        f.sync_all()?;
+        Ok(Some(torn))
+    }
+
+    // The interrupted append at the end of the log, with the chain head of the
+    // records before it. Any other failure is an error. The caller must hold
+    // the lock.
+    fn scan_torn_write(&self, dek: &[u8; 32]) -> std::io::Result<Option<(TornWriteReport, [u8; 32])>> {
+        let scan = record::scan(&self.path, Format::of(&self.header), dek)?;
+        let offset = match scan.failure {
+            None => return Ok(None),
+            Some(IntegrityFailure { offset, issue: IntegrityIssue::TornWrite }) => offset,
+            Some(failure) => return Err(to_io_err(VaultError::Integrity(failure))),
+        };
+        let bytes_dropped = std::fs::metadata(&self.path)?.len() - offset;
+        Ok(Some((TornWriteReport { offset, bytes_dropped, records_kept: scan.records.len() }, scan.head)))
+    }
+
+    // For an append that finds the log does not end in a trailer
+    fn torn_write_error(&self, dek: &[u8; 32]) -> std::io::Error {
+        match self.scan_torn_write(dek) {
+            Ok(Some((torn, _))) => to_io_err(VaultError::TornWrite(torn)),
+            Ok(None) => std::io::Error::new(std::io::ErrorKind::InvalidData, "the vault does not end in a trailer"),
+            Err(e) => e,
+        }
+    }
+
+    // Walk the whole record log and check every record and the hash chain
+    // against the trailer. Unlike reads, a failure is reported rather than
+    // returned as an error, together with the offset of the first bad record.
+    pub fn verify_integrity(&self, dek: &[u8; 32]) -> std::io::Result<IntegrityReport> {
+        let _lock = self.lock(LockMode::Shared)?;
+        let scan = record::scan(&self.path, Format::of(&self.header), dek)?;
+        Ok(IntegrityReport { records_checked: scan.records.len(), first_failure: scan.failure })
+    }
+
+    // Write `header` and `records` to a sibling temp file, fsync it and rename
+    // it over the vault. Records are sealed under `dek` with `header`'s cipher
+    // suite; chunk contents are read back from the current file, which is
+    // encrypted under `src_dek`.
+    // `progress` is called with (records written, total). The caller must hold
+    // the exclusive lock.
+    fn rewrite(
+        &self,
+        src_dek: &[u8; 32],
+        dek: &[u8; 32],
+        header: &VaultHeader,
+        records: &[Record],
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> std::io::Result<()> {
+        let tmp_path = self.write_temp(src_dek, dek, header, records, progress)?;
+        replace_file(&tmp_path, Path::new(&self.path))
+    }
+
+    // The first half of `rewrite`: returns the path of the synced temp file,
+    // which is removed again if anything fails
+    fn write_temp(
+        &self,
+        src_dek: &[u8; 32],
+        dek: &[u8; 32],
+        header: &VaultHeader,
+        records: &[Record],
+        progress: &mut dyn FnMut(usize, usize),
+    ) -> std::io::Result<String> {
+        let tmp_path = temp_path(&self.path);
+        let result = (|| {
+            let mut f = BufWriter::new(OpenOptions::new().create(true).truncate(true).write(true).open(&tmp_path)?);
+            write_header(&mut f, header)?;
+            let mut chain = Chain::new(dek)?;
+            let suite = header.cipher_suite;
+            let mut chunks = ChunkReader::open(&self.path, Format::of(&self.header))?;
+            // Every rewritten chunk is sealed against a new head, so it gets a
+            // new STREAM prefix too; its old nonce may already be on disk.
+            let mut prefixes: HashMap<Uuid, [u8; 7]> = HashMap::new();
+            for (i, record) in records.iter().enumerate() {
+                let bytes = match record {
+                    Record::Entry(e) => encode_record(dek, suite, RecordKind::Entry, e.id, &encode_entry(e)?, &chain.head)?,
+                    Record::Tombstone { id, deleted_at } => {
+                        encode_record(dek, suite, RecordKind::Tombstone, *id, &tombstone_payload(*deleted_at), &chain.head)?
+                    }
+                    Record::Attachment(a) => {
+                        encode_record(dek, suite, RecordKind::Attachment, a.id, &encode_attachment(a)?, &chain.head)?
+                    }
+                    Record::Chunk { id, nonce, at } => {
+                        let (_, _, data) = chunks.read(src_dek, *at)?;
+                        if !prefixes.contains_key(id) {
+                            prefixes.insert(*id, StreamNonce::random_prefix());
+                        }
+                        let nonce = StreamNonce { prefix: prefixes[id], ..*nonce };
+                        encode_chunk(dek, suite, *id, nonce, &data, &chain.head)?
+                    }
+                };
+                chain.advance(&bytes)?;
+                f.write_all(&bytes)?;
+                progress(i + 1, records.len());
             }
+            f.write_all(&chain.trailer())?;
+            f.into_inner().map_err(|e| e.into_error())?.sync_all()
+        })();
+        if let Err(e) = result {
+            let _ = std::fs::remove_file(&tmp_path);
+            return Err(e);
+        }
+        Ok(tmp_path)
+    }
+}
+
+// Records being appended in place of the trailer. The first one is written
+// with `PENDING_LEN` for its length until `finish` has synced the rest and the
+// new trailer, so a crash at any point before leaves a torn write at `start`,
+// which recovery truncates back to the log as it was.
+struct Appender {
+    w: BufWriter<File>,
+    data_start: u64,
+    start: u64,
+    // Where the next record goes
+    offset: u64,
+    chain: Chain,
+    // The real length prefix of the first record
+    first_len: Option<[u8; 4]>,
+}
+
+impl Appender {
+    // The head the next pushed record has to be sealed against
+    fn head(&self) -> [u8; 32] {
+        self.chain.head
+    }
+
+    // Returns where the record went
+    fn push(&mut self, bytes: Vec<u8>) -> std::io::Result<RecordRef> {
+        let at = RecordRef { offset: self.offset - self.data_start, prev: self.chain.head };
+        self.chain.advance(&bytes)?;
+        if self.first_len.is_none() {
+            self.first_len = Some(bytes[..4].try_into().expect("4 bytes"));
+            self.w.write_all(&PENDING_LEN)?;
+            self.w.write_all(&bytes[4..])?;
+        } else {
+            self.w.write_all(&bytes)?;
+        }
+        self.offset += bytes.len() as u64;
+        Ok(at)
+    }
+
+    // Returns the new chain head
+    fn finish(mut self) -> std::io::Result<[u8; 32]> {
+        self.w.write_all(&self.chain.trailer())?;
+        let mut f = self.w.into_inner().map_err(|e| e.into_error())?;
+        f.sync_all()?;
+        // The records only count once this lands; a crash before it leaves
+        // them behind a torn length prefix
+        if let Some(len) = self.first_len {
+            f.seek(SeekFrom::Start(self.start))?;
+            f.write_all(&len)?;
+            f.sync_all()?;
         }
-        
-        Ok(active_entries.into_iter().collect())
+        Ok(self.chain.head)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CompactionReport {
+    pub entries_kept: usize,
+    // Entry records kept, counting the older versions retained for history
+    // and the entries still in the trash
+    pub versions_kept: usize,
+    pub bytes_reclaimed: u64,
+}
+
+// What recovering from an interrupted append drops. The bytes from `offset`
+// on cannot be split into records, because the first length prefix already
+// runs past the end of the file, so only their size is known. A crash loses
+// at most the one append; a much longer tail means the log was cut short by
+// something else, and the user should be told before it is dropped. A log
+// cut at a record boundary is not a torn write at all but an integrity
+// failure, since appends never leave one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TornWriteReport {
+    pub offset: u64,
+    pub bytes_dropped: u64,
+    // Complete records before `offset`, which recovery keeps
+    pub records_kept: usize,
+}
+
+// How `UnlockedVault::unlock_with_options` opens a vault; the default is what
+// `UnlockedVault::unlock_with` does
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UnlockOptions {
+    // Drop an interrupted write from the end of the log, as
+    // `UnlockedVault::unlock_and_recover` does
+    pub recover: bool,
+    // Upgrade a format that predates header signing, as
+    // `UnlockedVault::unlock_and_migrate` does
+    pub migrate_unsigned: bool,
+    // Re-seal the credential's slot if it is below this, as
+    // `UnlockedVault::unlock_with_policy` does
+    pub kdf_policy: Option<KdfPolicy>,
+}
+
+pub const DEFAULT_HISTORY_VERSIONS: usize = 10;
+pub const DEFAULT_TRASH_DAYS: u64 = 30;
+
+// What `VaultStore::compact` keeps besides the latest version of each entry
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    // Older versions kept per entry; 0 keeps no history
+    pub history_versions: usize,
+    // Trashed entries are purged once they were deleted this many days ago;
+    // 0 purges the whole trash at every compaction
+    pub trash_days: u64,
+}
+
+impl Default for RetentionPolicy {
+    fn default() -> Self {
+        Self { history_versions: DEFAULT_HISTORY_VERSIONS, trash_days: DEFAULT_TRASH_DAYS }
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TrashedEntry {
+    pub id: Uuid,
+    pub title: String,
+    // Unix seconds; 0 if deleted before the trash existed
+    pub deleted_at: u64,
+}
+
+// Header format: len (u32 LE) | postcard(VaultHeader)
+fn read_header(path: &str) -> std::io::Result<VaultHeader> {
+    let mut f = File::open(path)?;
+    let mut len_bytes = [0u8; 4];
+    f.read_exact(&mut len_bytes)?;
+    let mut buf = vec![0u8; u32::from_le_bytes(len_bytes) as usize];
+    f.read_exact(&mut buf)?;
+    migrate::decode_header(&buf)
+}
+
+fn write_header<W: Write>(w: &mut W, header: &VaultHeader) -> std::io::Result<()> {
+    let header_bytes = postcard::to_stdvec(header).map_err(to_io_err)?;
+    let len = header_bytes.len() as u32;
+    w.write_all(&len.to_le_bytes())?;
+    w.write_all(&header_bytes)
+}
+
+fn temp_path(path: &str) -> String {
+    format!("{}.tmp", path)
+}
+
+// Atomically move a fully written and synced temp file over `dest`, then sync
+// the parent directory so the rename itself survives a crash.
+fn replace_file(tmp: &str, dest: &Path) -> std::io::Result<()> {
+    std::fs::rename(tmp, dest)?;
+    sync_parent_dir(dest)
+}
+
+// Directory entries (new files, renames) are only durable once the directory
+// itself is synced. Windows has no equivalent, so this is a no-op there.
+fn sync_parent_dir(path: &Path) -> std::io::Result<()> {
+    #[cfg(unix)]
+    {
+        let parent = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        File::open(parent)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+// An opened vault together with its unwrapped DEK. The master password is only
+// needed once, in `unlock`; the DEK is wiped when the session is dropped.
+pub struct UnlockedVault {
+    store: VaultStore,
+    dek: Zeroizing<[u8; 32]>,
+    index: EntryIndex,
+    kdf_upgrade: Option<KdfUpgrade>,
+    recovered: Option<TornWriteReport>,
+}
+
+impl UnlockedVault {
+    pub fn unlock<P: AsRef<Path>>(path: P, master_password: &[u8]) -> std::io::Result<Self> {
+        Self::unlock_with(path, Credential::MasterPassword(master_password))
+    }
+
+    // `recovery_key` is the printed form returned by `generate_recovery_key`
+    pub fn unlock_with_recovery_key<P: AsRef<Path>>(path: P, recovery_key: &str) -> std::io::Result<Self> {
+        let key = Zeroizing::new(parse_recovery_key(recovery_key).map_err(to_io_err)?);
+        Self::unlock_with(path, Credential::RecoveryKey(key.as_ref()))
+    }
+
+    // Fails with `VaultError::TornWrite` if a crash interrupted the last write;
+    // the caller can then show the user what would be dropped and ask before
+    // retrying with `unlock_and_recover`. Any other record that does not
+    // verify, or a chain that does not end at the trailer's head, fails with
+    // `VaultError::Integrity`.
+    pub fn unlock_with<P: AsRef<Path>>(path: P, credential: Credential) -> std::io::Result<Self> {
+        Self::unlock_with_options(path, credential, &UnlockOptions::default())
+    }
+
+    // Same as `unlock_with`, then re-seal the credential's slot if its KDF
+    // costs are below `policy`. `kdf_upgrade` reports what changed.
+    pub fn unlock_with_policy<P: AsRef<Path>>(
+        path: P,
+        credential: Credential,
+        policy: &KdfPolicy,
+    ) -> std::io::Result<Self> {
+        Self::unlock_with_options(path, credential, &UnlockOptions { kdf_policy: Some(*policy), ..Default::default() })
+    }
+
+    // Same as `unlock_with`, but first drops an interrupted write from the end
+    // of the log. The entry being saved is lost unless its record was complete.
+    // `recovered` reports what was dropped.
+    pub fn unlock_and_recover<P: AsRef<Path>>(path: P, credential: Credential) -> std::io::Result<Self> {
+        Self::unlock_with_options(path, credential, &UnlockOptions { recover: true, ..Default::default() })
+    }
+
+    // Same as `unlock_with`, but also for a vault whose format predates header
+    // signing (v5 and older), which the other ways of unlocking refuse with
+    // `VaultError::UnsignedHeader`. Upgrading signs the header with the DEK,
+    // vouching for whatever it says; a signed vault relabelled as an old
+    // format would pass that way too, so the user should confirm first.
+    pub fn unlock_and_migrate<P: AsRef<Path>>(path: P, credential: Credential) -> std::io::Result<Self> {
+        Self::unlock_with_options(path, credential, &UnlockOptions { migrate_unsigned: true, ..Default::default() })
+    }
+
+    // Any combination of the above. An unsigned header is refused before a
+    // torn write is looked at, so a refusal never changes the file.
+    pub fn unlock_with_options<P: AsRef<Path>>(
+        path: P,
+        credential: Credential,
+        options: &UnlockOptions,
+    ) -> std::io::Result<Self> {
+        let mut store = VaultStore::open(path)?;
+        let (slot, dek) = store.unlock_slot(credential)?;
+        if !store.header.is_signed() && !options.migrate_unsigned {
+            return Err(to_io_err(VaultError::UnsignedHeader { version: store.header.version }));
+        }
+        let mut recovered = None;
+        if options.recover {
+            recovered = store.recover_torn_write(&dek)?;
+        } else if store.find_torn_write()?.is_some() {
+            // Only decrypt the log to report on it once there is something to report
+            if let Some(torn) = store.check_torn_write(&dek)? {
+                return Err(to_io_err(VaultError::TornWrite(torn)));
+            }
+        }
+        store.upgrade(&dek)?;
+        let kdf_upgrade = match &options.kdf_policy {
+            Some(policy) => store.upgrade_slot_kdf(slot, credential, &dek, policy)?,
+            None => None,
+        };
+        let mut vault = Self { store, dek, index: EntryIndex::default(), kdf_upgrade, recovered };
+        // Building the index walks the whole chain, so records that were
+        // dropped, reordered or replayed fail here rather than on some read
+        vault.sync_index()?;
+        Ok(vault)
+    }
+
+    pub fn store(&self) -> &VaultStore {
+        &self.store
+    }
+
+    // The key slot re-sealed on unlock, if it was below the KDF policy
+    pub fn kdf_upgrade(&self) -> Option<&KdfUpgrade> {
+        self.kdf_upgrade.as_ref()
+    }
+
+    // The interrupted write dropped on unlock, if recovery was asked for and
+    // there was one
+    pub fn recovered(&self) -> Option<&TornWriteReport> {
+        self.recovered.as_ref()
+    }
+
+    pub fn set_lock_timeout(&mut self, timeout: Duration) {
+        self.store.set_lock_timeout(timeout);
+    }
+
+    pub fn set_retention(&mut self, retention: RetentionPolicy) {
+        self.store.set_retention(retention);
+    }
+
+    pub fn write_entry(&mut self, entry: &VaultEntry) -> std::io::Result<()> {
+        let payload = encode_entry(entry)?;
+        let _lock = self.lock_for_append()?;
+        let (at, head) =
+            self.store.append_record_locked(&self.dek, self.index.head(), RecordKind::Entry, entry.id, &payload)?;
+        self.index.appended_entry(entry, at, head);
+        Ok(())
+    }
+
+    // Served from the index; nothing is decrypted unless it has to be rebuilt
+    pub fn list_entries(&mut self) -> std::io::Result<Vec<(Uuid, String)>> {
+        let _lock = self.sync_index()?;
+        Ok(self.index.list())
+    }
+
+    pub fn list_active_entries(&mut self) -> std::io::Result<Vec<(Uuid, String)>> {
+        self.list_entries()
+    }
+
+    // Decrypts only the latest record for `id`
+    pub fn get_entry(&mut self, id: Uuid) -> std::io::Result<Option<VaultEntry>> {
+        let _lock = self.sync_index()?;
+        let at = match self.index.get(id) {
+            Some(at) => at,
+            None => return Ok(None),
+        };
+        match record::read_at(&self.store.path, Format::of(&self.store.header), &self.dek, at)? {
+            Record::Entry(e) if e.id == id => Ok(Some(e)),
+            _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "entry index is out of date")),
+        }
+    }
+
+    // Live entries whose title, tags, username, URLs or notes match every word
+    // of `query`, best first. Words match exactly, as a prefix, or with a typo
+    // or two; passwords are not searched.
+    pub fn search(&mut self, query: &str) -> std::io::Result<Vec<Uuid>> {
+        let _lock = self.sync_index()?;
+        Ok(self.index.search(query))
+    }
+
+    pub fn update_entry(&mut self, entry: VaultEntry) -> std::io::Result<()> {
+        self.write_entry(&entry)
+    }
+
+    pub fn entry_history(&self, id: Uuid) -> std::io::Result<Vec<VaultEntry>> {
+        self.store.entry_history(&self.dek, id)
+    }
+
+    pub fn restore_version(&mut self, id: Uuid, n: usize) -> std::io::Result<VaultEntry> {
+        let entry = self.store.version_to_restore(&self.dek, id, n)?;
+        self.write_entry(&entry)?;
+        Ok(entry)
+    }
+
+    pub fn delete_entry(&mut self, id: Uuid) -> std::io::Result<()> {
+        let payload = tombstone_payload(unix_now());
+        let _lock = self.lock_for_append()?;
+        let (at, head) =
+            self.store.append_record_locked(&self.dek, self.index.head(), RecordKind::Tombstone, id, &payload)?;
+        self.index.appended_tombstone(id, at, head);
+        Ok(())
+    }
+
+    pub fn list_trash(&self) -> std::io::Result<Vec<TrashedEntry>> {
+        self.store.list_trash(&self.dek)
+    }
+
+    pub fn restore_from_trash(&mut self, id: Uuid) -> std::io::Result<VaultEntry> {
+        let entry = self.store.entry_to_untrash(&self.dek, id)?;
+        self.write_entry(&entry)?;
+        Ok(entry)
+    }
+
+    pub fn empty_trash(&mut self) -> std::io::Result<CompactionReport> {
+        self.index.invalidate();
+        self.store.empty_trash(&self.dek)
+    }
+
+    pub fn compact(&mut self) -> std::io::Result<CompactionReport> {
+        self.index.invalidate();
+        self.store.compact(&self.dek)
+    }
+
+    pub fn verify_integrity(&self) -> std::io::Result<IntegrityReport> {
+        self.store.verify_integrity(&self.dek)
+    }
+
+    pub fn change_master_password(
+        &mut self,
+        old: Credential,
+        new: Credential,
+        new_params: Option<ArgonParams>,
+    ) -> std::io::Result<()> {
+        self.store.change_master_password(old, new, new_params)
+    }
+
+    pub fn rotate_dek(&mut self, credential: Credential, progress: impl FnMut(usize, usize)) -> std::io::Result<()> {
+        self.index.invalidate();
+        self.dek = self.store.rotate_dek(credential, progress)?;
+        Ok(())
+    }
+
+    pub fn add_key_slot(&mut self, credential: Credential, params: ArgonParams) -> std::io::Result<u32> {
+        self.store.add_key_slot(&self.dek, credential, params)
+    }
+
+    pub fn remove_key_slot(&mut self, id: u32) -> std::io::Result<()> {
+        self.store.remove_key_slot(&self.dek, id)
+    }
+
+    // Enrol a new printable recovery key and return it for the user to write
+    // down. Any previously issued recovery key stops working.
+    pub fn generate_recovery_key(&mut self) -> std::io::Result<String> {
+        let printed = generate_recovery_key();
+        let key = Zeroizing::new(parse_recovery_key(&printed).map_err(to_io_err)?);
+        self.store.replace_recovery_key(&self.dek, key.as_ref())?;
+        Ok(printed)
+    }
+
+    // Take the shared lock and bring the index up to date. The lock is
+    // returned so offsets stay valid while used.
+    fn sync_index(&mut self) -> std::io::Result<VaultLock> {
+        let lock = self.store.lock(LockMode::Shared)?;
+        self.refresh_index()?;
+        Ok(lock)
+    }
+
+    // Take the exclusive lock and bring the index up to date, so an append
+    // follows the log as it is and the index can take it in
+    pub(crate) fn lock_for_append(&mut self) -> std::io::Result<VaultLock> {
+        self.store.ensure_current()?;
+        let lock = self.store.lock(LockMode::Exclusive)?;
+        self.refresh_index()?;
+        Ok(lock)
+    }
+
+    // Rebuild the index if the trailer shows another process wrote to the log.
+    // The header is re-read first, since that process may have re-signed it
+    // under a new DEK or changed the key slots. The caller must hold the lock.
+    fn refresh_index(&mut self) -> std::io::Result<()> {
+        if self.index.is_current(record::trailer_head(&self.store.path)?) {
+            return Ok(());
+        }
+        self.store.header = self.store.current_header(&self.dek)?;
+        let scan = record::scan(&self.store.path, Format::of(&self.store.header), &self.dek)?;
+        if let Some(failure) = scan.failure {
+            return Err(to_io_err(VaultError::Integrity(failure)));
+        }
+        self.index = EntryIndex::build(&scan);
+        Ok(())
     }
 }
 
@@ -173,10 +1164,107 @@ fn to_io_err<E: std::error::Error + Send + Sync + 'static>(e: E) -> std::io::Err
     std::io::Error::new(std::io::ErrorKind::InvalidData, e)
 }
 
+pub const ENTRY_SCHEMA_V1: u8 = 1;
+pub const ENTRY_SCHEMA_V2: u8 = 2;
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct VaultEntry {
     pub id: Uuid,
     pub title: String,
     pub username: String,
     pub password: String,
+    pub urls: Vec<String>,
+    pub notes: String,
+    pub custom_fields: Vec<CustomField>,
+    pub tags: Vec<String>,
+    pub created_at: u64,  // unix seconds
+    pub modified_at: u64, // unix seconds
+}
+
+impl VaultEntry {
+    pub fn new(title: String, username: String, password: String) -> Self {
+        let now = unix_now();
+        Self {
+            id: Uuid::new_v4(),
+            title,
+            username,
+            password,
+            urls: Vec::new(),
+            notes: String::new(),
+            custom_fields: Vec::new(),
+            tags: Vec::new(),
+            created_at: now,
+            modified_at: now,
+        }
+    }
+
+    // Bump the modification time before writing a new version
+    pub fn touch(&mut self) {
+        self.modified_at = unix_now();
+    }
+}
+
+// Extra named value such as a PIN or recovery code. Concealed fields are
+// treated like the password: masked in the UI and never searchable.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CustomField {
+    pub name: String,
+    pub value: String,
+    pub concealed: bool,
+}
+
+// Entry layout written before ENTRY_SCHEMA_V2; kept so old records still decode
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct VaultEntryV1 {
+    pub id: Uuid,
+    pub title: String,
+    pub username: String,
+    pub password: String,
+}
+
+impl From<VaultEntryV1> for VaultEntry {
+    fn from(e: VaultEntryV1) -> Self {
+        Self {
+            id: e.id,
+            title: e.title,
+            username: e.username,
+            password: e.password,
+            urls: Vec::new(),
+            notes: String::new(),
+            custom_fields: Vec::new(),
+            tags: Vec::new(),
+            created_at: 0,
+            modified_at: 0,
+        }
+    }
+}
+
+// Entry payload: schema(1) | postcard(VaultEntry)
+fn encode_entry(entry: &VaultEntry) -> std::io::Result<Vec<u8>> {
+    let mut out = vec![ENTRY_SCHEMA_V2];
+    postcard::to_io(entry, &mut out).map_err(to_io_err)?;
+    Ok(out)
+}
+
+// Formats before 3 stored a bare postcard(VaultEntryV1) with no schema byte
+fn decode_entry(format_version: u16, pt: &[u8]) -> std::io::Result<VaultEntry> {
+    if format_version < 3 {
+        let v1: VaultEntryV1 = postcard::from_bytes(pt).map_err(to_io_err)?;
+        return Ok(v1.into());
+    }
+    match pt.split_first() {
+        Some((&ENTRY_SCHEMA_V1, rest)) => {
+            let v1: VaultEntryV1 = postcard::from_bytes(rest).map_err(to_io_err)?;
+            Ok(v1.into())
+        }
+        Some((&ENTRY_SCHEMA_V2, rest)) => postcard::from_bytes(rest).map_err(to_io_err),
+        _ => Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "unknown entry schema")),
+    }
+}
+
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
 }
@@ -0,0 +1,54 @@
+use crate::{to_io_err, VaultError};
+use std::fs::{File, OpenOptions, TryLockError};
+use std::time::{Duration, Instant};
+
+pub const DEFAULT_LOCK_TIMEOUT: Duration = Duration::from_secs(5);
+
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+
+// Advisory lock on `<vault>.lock`, released when dropped. The vault file itself
+// is replaced by rename on every rewrite, so a lock held on it would end up on
+// an unlinked inode; the sibling lock file stays put.
+//
+// Locks are per open file, not per process: never take a second lock on the
+// same vault while holding one, or it waits for itself until the timeout.
+pub(crate) struct VaultLock {
+    _file: File,
+}
+
+#[derive(Clone, Copy)]
+pub(crate) enum LockMode {
+    // Readers; any number may hold it at once
+    Shared,
+    // Writers; excludes every other reader and writer
+    Exclusive,
+}
+
+impl VaultLock {
+    // Poll until the lock is granted, or fail with `VaultError::VaultLocked`
+    // once `timeout` has passed
+    pub(crate) fn acquire(vault_path: &str, mode: LockMode, timeout: Duration) -> std::io::Result<Self> {
+        let file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(lock_path(vault_path))?;
+        let deadline = Instant::now() + timeout;
+        loop {
+            let attempt = match mode {
+                LockMode::Shared => file.try_lock_shared(),
+                LockMode::Exclusive => file.try_lock(),
+            };
+            match attempt {
+                Ok(()) => return Ok(Self { _file: file }),
+                Err(TryLockError::WouldBlock) => {}
+                Err(TryLockError::Error(e)) => return Err(e),
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(to_io_err(VaultError::VaultLocked));
+            }
+            std::thread::sleep(POLL_INTERVAL.min(deadline - now));
+        }
+    }
+}
+
+fn lock_path(vault_path: &str) -> String {
+    format!("{}.lock", vault_path)
+}
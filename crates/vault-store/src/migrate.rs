@@ -0,0 +1,236 @@
+// Older vault formats and how a vault is brought up to FORMAT_VERSION.
+//
+// Every historical header layout has a decoder here, and each layout converts
+// into the next one, so an old header is lifted one step at a time. The lifted
+// header keeps the version it was read from, which is what the record decoders
+// (`record::decode_record`, keyed on `Format`) use to read the log as it was
+// written. Migrating re-encodes every record in the current format.
+
+use crate::lock::LockMode;
+use crate::{read_header, replace_file, to_io_err, KeySlot, SlotType, VaultError, VaultHeader, VaultStore};
+use crate::{FORMAT_VERSION, MAGIC};
+use crate::{ArgonParamsOnDisk, CipherSuite};
+use serde::{Deserialize, Serialize};
+
+// One version bump and what it changed on disk
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MigrationStep {
+    pub from: u16,
+    pub to: u16,
+    pub description: &'static str,
+}
+
+const fn step(from: u16, description: &'static str) -> MigrationStep {
+    MigrationStep { from, to: from + 1, description }
+}
+
+// One entry per version bump, oldest first
+const STEPS: [MigrationStep; 8] = [
+    step(1, "records carry a kind byte, and deletions become tombstone records"),
+    step(2, "entry payloads carry a schema byte; entries gain URLs, notes, custom fields, tags and timestamps"),
+    step(3, "the single password-wrapped DEK becomes key slot 0"),
+    step(4, "the header records whether password slots need a keyfile"),
+    step(5, "the header is signed with a DEK-derived Ed25519 key"),
+    step(6, "records are chained by an HMAC head, kept in a trailer after the last record"),
+    step(7, "attachment and chunk records"),
+    step(8, "the header names the cipher suite; existing vaults stay on AES-256-GCM"),
+];
+
+// What `VaultStore::migrate` did, or would do in a dry run
+#[derive(Debug, Clone)]
+pub struct MigrationReport {
+    pub from_version: u16,
+    pub to_version: u16,
+    // Empty if the vault was already current
+    pub steps: Vec<MigrationStep>,
+    pub records: usize,
+    pub bytes_before: u64,
+    pub bytes_after: u64,
+    pub dry_run: bool,
+}
+
+impl VaultStore {
+    // Decode every record with the decoders of the version the vault was
+    // written in and write them out again in the current format, with the
+    // lifted header re-signed. History, the trash and attachments are kept.
+    // With `dry_run` the migrated file is written and measured but thrown away,
+    // so a migration that would fail fails here without touching the vault.
+    pub fn migrate(&mut self, dek: &[u8; 32], dry_run: bool) -> std::io::Result<MigrationReport> {
+        let from_version = self.header.version;
+        let steps: Vec<MigrationStep> = STEPS.iter().filter(|s| s.from >= from_version).copied().collect();
+        let bytes_before = std::fs::metadata(&self.path)?.len();
+        let mut report = MigrationReport {
+            from_version,
+            to_version: from_version,
+            steps,
+            records: 0,
+            bytes_before,
+            bytes_after: bytes_before,
+            dry_run,
+        };
+        if report.steps.is_empty() {
+            return Ok(report);
+        }
+        // Exclusive even for a dry run, which writes the same temp file
+        let _lock = self.lock(LockMode::Exclusive)?;
+        // Another process may have migrated the vault since this store read
+        // it; its records are no longer in the format this would read
+        if read_header(&self.path)?.version != from_version {
+            return Err(to_io_err(VaultError::VaultChanged));
+        }
+        // The new header is signed with `dek`, so a signed one has to be ours
+        // already. Unsigned ones are taken as they are.
+        if self.header.is_signed() {
+            self.header.verify_signer(dek)?;
+        }
+        let records = self.read_records(dek)?;
+        let mut header = self.header.clone();
+        header.version = FORMAT_VERSION;
+        header.sign(dek)?;
+        let tmp_path = self.write_temp(dek, dek, &header, &records, &mut |_, _| {})?;
+        report.to_version = FORMAT_VERSION;
+        report.records = records.len();
+        report.bytes_after = std::fs::metadata(&tmp_path)?.len();
+        if dry_run {
+            std::fs::remove_file(&tmp_path)?;
+        } else {
+            replace_file(&tmp_path, std::path::Path::new(&self.path))?;
+            self.header = header;
+        }
+        Ok(report)
+    }
+}
+
+// Common prefix of every header version, decoded first to pick the layout
+#[derive(Deserialize)]
+struct HeaderPrefix {
+    magic: [u8; 5],
+    version: u16,
+}
+
+// Decode a header of any supported version into the current layout. Signed
+// headers (v6 on) must carry a valid signature.
+pub(crate) fn decode_header(buf: &[u8]) -> std::io::Result<VaultHeader> {
+    let (prefix, _) = postcard::take_from_bytes::<HeaderPrefix>(buf).map_err(to_io_err)?;
+    if prefix.magic != MAGIC || prefix.version == 0 || prefix.version > FORMAT_VERSION {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "invalid vault header"));
+    }
+    let header = match prefix.version {
+        1..=3 => lift(VaultHeaderV5::from(VaultHeaderV4::from(parse::<VaultHeaderV1>(buf)?))),
+        4 => lift(VaultHeaderV5::from(parse::<VaultHeaderV4>(buf)?)),
+        5 => lift(parse::<VaultHeaderV5>(buf)?),
+        6..=8 => parse::<VaultHeaderV6>(buf)?.into(),
+        _ => parse::<VaultHeader>(buf)?,
+    };
+    if header.is_signed() {
+        header.verify_signature()?;
+    }
+    Ok(header)
+}
+
+fn parse<'a, T: Deserialize<'a>>(buf: &'a [u8]) -> std::io::Result<T> {
+    postcard::from_bytes(buf).map_err(to_io_err)
+}
+
+fn lift(h: VaultHeaderV5) -> VaultHeader {
+    VaultHeaderV6::from(h).into()
+}
+
+// v1-v3: a single password-wrapped DEK
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct VaultHeaderV1 {
+    magic: [u8; 5],
+    version: u16,
+    kdf_params: ArgonParamsOnDisk,
+    salt_kek: [u8; 32],
+    wrapped_dek: Vec<u8>,
+}
+
+// v4: key slots
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct VaultHeaderV4 {
+    magic: [u8; 5],
+    version: u16,
+    key_slots: Vec<KeySlot>,
+}
+
+impl From<VaultHeaderV1> for VaultHeaderV4 {
+    fn from(h: VaultHeaderV1) -> Self {
+        let slot = KeySlot {
+            id: 0,
+            slot_type: SlotType::MasterPassword,
+            kdf_params: h.kdf_params,
+            salt: h.salt_kek,
+            wrapped_dek: h.wrapped_dek,
+        };
+        Self { magic: h.magic, version: h.version, key_slots: vec![slot] }
+    }
+}
+
+// v5: keyfile flag
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct VaultHeaderV5 {
+    magic: [u8; 5],
+    version: u16,
+    key_slots: Vec<KeySlot>,
+    keyfile_required: bool,
+}
+
+impl From<VaultHeaderV4> for VaultHeaderV5 {
+    fn from(h: VaultHeaderV4) -> Self {
+        Self { magic: h.magic, version: h.version, key_slots: h.key_slots, keyfile_required: false }
+    }
+}
+
+// v6-v8: signed, AES-256-GCM only. Headers of these versions are also signed
+// in this layout, see `VaultHeader::signed_bytes`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) struct VaultHeaderV6 {
+    magic: [u8; 5],
+    version: u16,
+    key_slots: Vec<KeySlot>,
+    keyfile_required: bool,
+    verify_key: [u8; 32],
+    signature: Vec<u8>,
+}
+
+// Unsigned until the migrated header is signed with the DEK
+impl From<VaultHeaderV5> for VaultHeaderV6 {
+    fn from(h: VaultHeaderV5) -> Self {
+        Self {
+            magic: h.magic,
+            version: h.version,
+            key_slots: h.key_slots,
+            keyfile_required: h.keyfile_required,
+            verify_key: [0u8; 32],
+            signature: Vec::new(),
+        }
+    }
+}
+
+impl From<VaultHeaderV6> for VaultHeader {
+    fn from(h: VaultHeaderV6) -> Self {
+        Self {
+            magic: h.magic,
+            version: h.version,
+            key_slots: h.key_slots,
+            keyfile_required: h.keyfile_required,
+            cipher_suite: CipherSuite::Aes256Gcm,
+            verify_key: h.verify_key,
+            signature: h.signature,
+        }
+    }
+}
+
+impl From<VaultHeader> for VaultHeaderV6 {
+    fn from(h: VaultHeader) -> Self {
+        Self {
+            magic: h.magic,
+            version: h.version,
+            key_slots: h.key_slots,
+            keyfile_required: h.keyfile_required,
+            verify_key: h.verify_key,
+            signature: h.signature,
+        }
+    }
+}
@@ -0,0 +1,634 @@
+use crate::attachment::{decode_attachment, Attachment};
+use crate::{decode_entry, to_io_err, VaultEntry, VaultError, VaultHeader};
+use core_crypto::{
+    aead_decrypt, aead_encrypt, chain_mac, derive_chain_key, derive_item_key, macs_equal, CipherSuite, StreamNonce,
+};
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use uuid::Uuid;
+use zeroize::Zeroizing;
+
+// Trailer format (v7+): magic(4) | head MAC(32). It is always the last thing in
+// the file and is rewritten after every append.
+pub(crate) const TRAILER_MAGIC: [u8; 4] = *b"SVTR";
+pub(crate) const TRAILER_LEN: u64 = 4 + 32;
+// Stands in for the length prefix of the first record of an append until the
+// append is synced. No log is that long, so it always reads as a torn write.
+pub(crate) const PENDING_LEN: [u8; 4] = [0xff; 4];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub(crate) enum RecordKind {
+    Entry = 1,
+    Tombstone = 2,
+    Attachment = 3,
+    Chunk = 4,
+}
+
+impl RecordKind {
+    fn from_byte(b: u8) -> Option<Self> {
+        match b {
+            1 => Some(RecordKind::Entry),
+            2 => Some(RecordKind::Tombstone),
+            3 => Some(RecordKind::Attachment),
+            4 => Some(RecordKind::Chunk),
+            _ => None,
+        }
+    }
+}
+
+pub(crate) enum Record {
+    Entry(VaultEntry),
+    // Moves the entry to the trash; its versions stay until it is purged.
+    // `deleted_at` is 0 for tombstones written before the trash existed.
+    Tombstone { id: Uuid, deleted_at: u64 },
+    // Commits an attachment whose chunks were written just before it. A
+    // tombstone for the attachment's ID removes it.
+    Attachment(Attachment),
+    // Part of an attachment's content. Only its position is kept; the
+    // plaintext is read again when the attachment is extracted.
+    Chunk { id: Uuid, nonce: StreamNonce, at: RecordRef },
+}
+
+impl Record {
+    pub(crate) fn id(&self) -> Uuid {
+        match self {
+            Record::Entry(e) => e.id,
+            Record::Tombstone { id, .. } | Record::Chunk { id, .. } => *id,
+            Record::Attachment(a) => a.id,
+        }
+    }
+}
+
+// Tombstone payload: deletion time in unix seconds (u64 LE)
+pub(crate) fn tombstone_payload(deleted_at: u64) -> [u8; 8] {
+    deleted_at.to_le_bytes()
+}
+
+// IDs whose most recent record is an entry, mapped to that record's position
+pub(crate) fn live_ids(records: &[Record]) -> HashMap<Uuid, usize> {
+    let mut live = HashMap::new();
+    for (i, record) in records.iter().enumerate() {
+        match record {
+            Record::Entry(e) => { live.insert(e.id, i); }
+            Record::Tombstone { id, .. } => { live.remove(id); }
+            Record::Attachment(_) | Record::Chunk { .. } => {}
+        }
+    }
+    live
+}
+
+// Latest version of each live entry, in the order those versions were written
+pub(crate) fn latest_live_entries(records: Vec<Record>) -> Vec<VaultEntry> {
+    retained_records(records, 0, u64::MAX)
+        .into_iter()
+        .filter_map(|r| match r {
+            Record::Entry(e) => Some(e),
+            _ => None,
+        })
+        .collect()
+}
+
+// Latest version of each trashed entry with its deletion time, most recently
+// deleted first
+pub(crate) fn trashed_entries(records: Vec<Record>) -> Vec<(VaultEntry, u64)> {
+    let mut latest: HashMap<Uuid, VaultEntry> = HashMap::new();
+    let mut deleted: HashMap<Uuid, u64> = HashMap::new();
+    for record in records {
+        match record {
+            Record::Entry(e) => {
+                deleted.remove(&e.id);
+                latest.insert(e.id, e);
+            }
+            Record::Tombstone { id, deleted_at } => {
+                deleted.insert(id, deleted_at);
+            }
+            Record::Attachment(_) | Record::Chunk { .. } => {}
+        }
+    }
+    let mut out: Vec<(VaultEntry, u64)> =
+        deleted.into_iter().filter_map(|(id, at)| latest.remove(&id).map(|e| (e, at))).collect();
+    out.sort_unstable_by_key(|(_, deleted_at)| std::cmp::Reverse(*deleted_at));
+    out
+}
+
+// Attachments that were committed and not removed since, with their chunks in
+// log order. Chunks left by an interrupted add have no attachment record.
+pub(crate) fn live_attachments(records: &[Record]) -> Vec<(Attachment, Vec<RecordRef>)> {
+    let mut chunks: HashMap<Uuid, Vec<RecordRef>> = HashMap::new();
+    let mut live: HashMap<Uuid, (usize, Attachment, Vec<RecordRef>)> = HashMap::new();
+    for (i, record) in records.iter().enumerate() {
+        match record {
+            Record::Chunk { id, at, .. } => chunks.entry(*id).or_default().push(*at),
+            Record::Attachment(a) => {
+                live.insert(a.id, (i, a.clone(), chunks.remove(&a.id).unwrap_or_default()));
+            }
+            Record::Tombstone { id, .. } => {
+                live.remove(id);
+            }
+            Record::Entry(_) => {}
+        }
+    }
+    let mut out: Vec<(usize, Attachment, Vec<RecordRef>)> = live.into_values().collect();
+    out.sort_unstable_by_key(|(i, _, _)| *i);
+    out.into_iter().map(|(_, a, chunks)| (a, chunks)).collect()
+}
+
+// What compaction keeps, in log order: the latest version of each live or
+// trashed entry plus up to `history` older ones, the tombstone of each trashed
+// entry, and the attachments of every kept entry. Entries trashed before
+// `purge_before` are dropped entirely, attachments included.
+pub(crate) fn retained_records(records: Vec<Record>, history: usize, purge_before: u64) -> Vec<Record> {
+    // Deletion time of each trashed ID; live IDs are absent
+    let mut trashed: HashMap<Uuid, u64> = HashMap::new();
+    let mut has_versions: HashMap<Uuid, usize> = HashMap::new();
+    for record in &records {
+        match record {
+            Record::Entry(e) => {
+                trashed.remove(&e.id);
+                has_versions.insert(e.id, history.saturating_add(1));
+            }
+            Record::Tombstone { id, deleted_at } => {
+                trashed.insert(*id, *deleted_at);
+            }
+            Record::Attachment(_) | Record::Chunk { .. } => {}
+        }
+    }
+    let mut budget = has_versions;
+    budget.retain(|id, _| trashed.get(id).is_none_or(|at| *at >= purge_before));
+    let attached: HashSet<Uuid> = live_attachments(&records)
+        .into_iter()
+        .filter(|(a, _)| budget.contains_key(&a.entry_id))
+        .map(|(a, _)| a.id)
+        .collect();
+
+    // Walk backwards so the newest versions use up each entry's budget first
+    let mut keep = vec![false; records.len()];
+    let mut seen: HashSet<Uuid> = HashSet::new();
+    for (i, record) in records.iter().enumerate().rev() {
+        let id = record.id();
+        let latest = seen.insert(id);
+        keep[i] = match record {
+            Record::Entry(_) => match budget.get_mut(&id).filter(|left| **left > 0) {
+                Some(left) => {
+                    *left -= 1;
+                    true
+                }
+                None => false,
+            },
+            // Only the tombstone that put a kept entry in the trash
+            Record::Tombstone { .. } => latest && budget.contains_key(&id),
+            Record::Attachment(_) => latest && attached.contains(&id),
+            Record::Chunk { .. } => attached.contains(&id),
+        };
+    }
+    records.into_iter().zip(keep).filter_map(|(record, keep)| keep.then_some(record)).collect()
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IntegrityIssue {
+    // Length prefix, kind byte or payload could not be parsed
+    MalformedRecord,
+    // AEAD tag did not verify: wrong key, edited bytes or a broken chain
+    AuthenticationFailed,
+    // The log ends part-way through a record or trailer, as left by a crash
+    // during an append. `VaultStore::recover_torn_write` truncates it.
+    TornWrite,
+    // Records are intact but end before (or after) the trailer's head MAC
+    HeadMismatch,
+}
+
+impl std::fmt::Display for IntegrityIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let msg = match self {
+            IntegrityIssue::MalformedRecord => "malformed record",
+            IntegrityIssue::AuthenticationFailed => "record failed authentication",
+            IntegrityIssue::TornWrite => "incomplete record at the end of the log",
+            IntegrityIssue::HeadMismatch => "records do not match the trailer (truncated or rolled back)",
+        };
+        f.write_str(msg)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IntegrityFailure {
+    // Byte offset in the vault file of the first record (or trailer) that failed
+    pub offset: u64,
+    pub issue: IntegrityIssue,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IntegrityReport {
+    pub records_checked: usize,
+    pub first_failure: Option<IntegrityFailure>,
+}
+
+impl IntegrityReport {
+    pub fn is_intact(&self) -> bool {
+        self.first_failure.is_none()
+    }
+}
+
+// Running MAC over the record log. The start value is keyed, so an attacker
+// cannot forge the trailer of an emptied vault either.
+pub(crate) struct Chain {
+    key: Zeroizing<[u8; 32]>,
+    pub(crate) head: [u8; 32],
+}
+
+impl Chain {
+    pub(crate) fn new(dek: &[u8; 32]) -> std::io::Result<Self> {
+        let key = Zeroizing::new(derive_chain_key(dek).map_err(to_io_err)?);
+        let head = chain_mac(&key, &[0u8; 32], b"chain-start").map_err(to_io_err)?;
+        Ok(Self { key, head })
+    }
+
+    // Continue from the head stored in an existing trailer
+    pub(crate) fn resume(dek: &[u8; 32], head: [u8; 32]) -> std::io::Result<Self> {
+        let key = Zeroizing::new(derive_chain_key(dek).map_err(to_io_err)?);
+        Ok(Self { key, head })
+    }
+
+    pub(crate) fn advance(&mut self, record: &[u8]) -> std::io::Result<()> {
+        self.head = chain_mac(&self.key, &self.head, record).map_err(to_io_err)?;
+        Ok(())
+    }
+
+    pub(crate) fn trailer(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(TRAILER_LEN as usize);
+        out.extend_from_slice(&TRAILER_MAGIC);
+        out.extend_from_slice(&self.head);
+        out
+    }
+}
+
+// What the header says about how records are framed and sealed
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct Format {
+    pub(crate) version: u16,
+    pub(crate) suite: CipherSuite,
+}
+
+impl Format {
+    pub(crate) fn of(header: &VaultHeader) -> Self {
+        Self { version: header.version, suite: header.cipher_suite }
+    }
+}
+
+// Formats before v7 end at the last record, without chain or trailer
+pub(crate) fn has_chain(version: u16) -> bool {
+    version >= 7
+}
+
+pub(crate) fn parse_trailer(bytes: &[u8]) -> Option<[u8; 32]> {
+    if bytes.len() as u64 != TRAILER_LEN || bytes[..4] != TRAILER_MAGIC {
+        return None;
+    }
+    bytes[4..].try_into().ok()
+}
+
+// The kind byte is authenticated so a tombstone cannot be turned into an entry;
+// in chained formats the previous head is too, so records cannot be moved
+fn record_aad(kind: RecordKind, id: &[u8; 16], prev: Option<&[u8; 32]>) -> Vec<u8> {
+    let mut aad = Vec::with_capacity(1 + 16 + 32);
+    aad.push(kind as u8);
+    aad.extend_from_slice(id);
+    if let Some(prev) = prev {
+        aad.extend_from_slice(prev);
+    }
+    aad
+}
+
+// Record format: len | kind(1) | id(16) | nonce | ct. The nonce is 12 bytes
+// for AES-256-GCM and 24 for XChaCha20-Poly1305.
+pub(crate) fn encode_record(
+    dek: &[u8; 32],
+    suite: CipherSuite,
+    kind: RecordKind,
+    id: Uuid,
+    payload: &[u8],
+    prev: &[u8; 32],
+) -> std::io::Result<Vec<u8>> {
+    seal_record(dek, suite, kind, id, &suite.random_nonce(), payload, prev)
+}
+
+// Chunks are sealed under the attachment's item key like any other record, but
+// with a STREAM nonce in place of a random one, see `StreamNonce`. Longer
+// nonces are padded with random bytes.
+pub(crate) fn encode_chunk(
+    dek: &[u8; 32],
+    suite: CipherSuite,
+    id: Uuid,
+    nonce: StreamNonce,
+    data: &[u8],
+    prev: &[u8; 32],
+) -> std::io::Result<Vec<u8>> {
+    let mut nonce_bytes = suite.random_nonce();
+    nonce_bytes[..12].copy_from_slice(&nonce.to_bytes());
+    seal_record(dek, suite, RecordKind::Chunk, id, &nonce_bytes, data, prev)
+}
+
+fn seal_record(
+    dek: &[u8; 32],
+    suite: CipherSuite,
+    kind: RecordKind,
+    id: Uuid,
+    nonce: &[u8],
+    payload: &[u8],
+    prev: &[u8; 32],
+) -> std::io::Result<Vec<u8>> {
+    let id_bytes = id.as_bytes();
+    let item_key = derive_item_key(dek, id_bytes).map_err(to_io_err)?;
+    let aad = record_aad(kind, id_bytes, Some(prev));
+    let ct = aead_encrypt(suite, &item_key, nonce, payload, &aad).map_err(to_io_err)?;
+    let total_len = 1 + 16 + nonce.len() + ct.len();
+    let mut out = Vec::with_capacity(4 + total_len);
+    out.extend_from_slice(&(total_len as u32).to_le_bytes());
+    out.push(kind as u8);
+    out.extend_from_slice(id_bytes);
+    out.extend_from_slice(nonce);
+    out.extend_from_slice(&ct);
+    Ok(out)
+}
+
+// Position of a record in the log, relative to the end of the header so that
+// header rewrites do not move it, and the chain head it was sealed against.
+// Together they are enough to decrypt the record on its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct RecordRef {
+    pub(crate) offset: u64,
+    pub(crate) prev: [u8; 32],
+}
+
+pub(crate) struct Scan {
+    pub(crate) records: Vec<Record>,
+    // Where each of `records` is stored
+    pub(crate) refs: Vec<RecordRef>,
+    pub(crate) failure: Option<IntegrityFailure>,
+    // Chain head after the last record that verified
+    pub(crate) head: [u8; 32],
+}
+
+// One step of walking the record log, before anything is decrypted
+enum Frame {
+    // Length prefix and body of a complete record
+    Record(Vec<u8>),
+    Trailer([u8; 32]),
+    End,
+    // The file ends inside a record or trailer: an interrupted append
+    Torn,
+    // A chained log ends after a complete record but has no trailer. Appends
+    // never leave this behind, so records were cut off rather than torn.
+    Unterminated,
+    Malformed,
+}
+
+// Sequential reader over the records after the header. Appends overwrite the
+// trailer with record || new trailer, and the first record's length prefix
+// reads `PENDING_LEN` until all of it is synced, so a crash mid-append leaves
+// a tail that is too short to hold what its length prefix promises.
+struct Frames {
+    r: BufReader<File>,
+    data_start: u64,
+    offset: u64,
+    file_len: u64,
+    chained: bool,
+    min_len: u64,
+}
+
+impl Frames {
+    fn open(path: &str, format: Format) -> std::io::Result<Self> {
+        let f = File::open(path)?;
+        let file_len = f.metadata()?.len();
+        let mut r = BufReader::new(f);
+        let mut len_bytes = [0u8; 4];
+        r.read_exact(&mut len_bytes)?;
+        let data_start = 4 + u32::from_le_bytes(len_bytes) as u64;
+        r.seek(SeekFrom::Start(data_start))?;
+        // v1 records have no kind byte
+        let nonce_len = format.suite.nonce_len() as u64;
+        let prefix_len = if format.version >= 2 { 1 + 16 + nonce_len } else { 16 + nonce_len };
+        let chained = has_chain(format.version);
+        Ok(Self { r, data_start, offset: data_start, file_len, chained, min_len: prefix_len + 16 })
+    }
+
+    fn next(&mut self) -> std::io::Result<Frame> {
+        let remaining = self.file_len - self.offset;
+        if remaining == 0 {
+            // Chained logs always end in a trailer
+            return Ok(if self.chained { Frame::Unterminated } else { Frame::End });
+        }
+        if remaining < 4 {
+            return Ok(Frame::Torn);
+        }
+        let mut raw = vec![0u8; 4];
+        if self.chained && remaining == TRAILER_LEN {
+            raw.resize(TRAILER_LEN as usize, 0);
+            self.r.read_exact(&mut raw)?;
+            if let Some(head) = parse_trailer(&raw) {
+                return Ok(Frame::Trailer(head));
+            }
+            // Records are longer than a trailer, so this is a record cut short
+            // or garbage; the length prefix below tells which
+            raw.truncate(4);
+        } else {
+            self.r.read_exact(&mut raw)?;
+        }
+        let clen = u32::from_le_bytes(raw[..4].try_into().expect("4 bytes")) as u64;
+        if clen < self.min_len {
+            return Ok(Frame::Malformed);
+        }
+        if clen > remaining - 4 {
+            return Ok(Frame::Torn);
+        }
+        raw.resize(4 + clen as usize, 0);
+        self.r.read_exact(&mut raw[4..])?;
+        Ok(Frame::Record(raw))
+    }
+}
+
+impl Frames {
+    // The complete record at `at`, as written by an earlier scan
+    fn record_at(&mut self, at: RecordRef) -> std::io::Result<Vec<u8>> {
+        self.offset = self.data_start + at.offset;
+        self.r.seek(SeekFrom::Start(self.offset))?;
+        let issue = match self.next()? {
+            Frame::Record(raw) => return Ok(raw),
+            Frame::Torn => IntegrityIssue::TornWrite,
+            Frame::Trailer(_) | Frame::End | Frame::Unterminated | Frame::Malformed => IntegrityIssue::MalformedRecord,
+        };
+        Err(integrity_error(self.offset, issue))
+    }
+}
+
+fn integrity_error(offset: u64, issue: IntegrityIssue) -> std::io::Error {
+    to_io_err(VaultError::Integrity(IntegrityFailure { offset, issue }))
+}
+
+// Offset of an incomplete record or trailer at the end of the log, if any.
+// Only lengths are checked, so this needs no key.
+pub(crate) fn find_torn_write(path: &str, format: Format) -> std::io::Result<Option<u64>> {
+    let mut frames = Frames::open(path, format)?;
+    loop {
+        let at = frames.offset;
+        match frames.next()? {
+            Frame::Record(raw) => frames.offset += raw.len() as u64,
+            Frame::Torn => return Ok(Some(at)),
+            Frame::Trailer(_) | Frame::End | Frame::Unterminated | Frame::Malformed => return Ok(None),
+        }
+    }
+}
+
+// Decrypt every record of the vault at `path`, stopping at the first one that
+// does not verify. Older formats are read with their own framing: v1 has no
+// kind byte, and nothing before v7 is chained or has a trailer.
+pub(crate) fn scan(path: &str, format: Format, dek: &[u8; 32]) -> std::io::Result<Scan> {
+    let mut frames = Frames::open(path, format)?;
+    let mut chain = Chain::new(dek)?;
+    let mut records = Vec::new();
+    let mut refs = Vec::new();
+    let failure = loop {
+        let at = frames.offset;
+        let issue = match frames.next()? {
+            Frame::Record(raw) => {
+                let record_ref = RecordRef { offset: at - frames.data_start, prev: chain.head };
+                match decode_record(dek, format, &raw[4..], record_ref) {
+                    Ok(record) => records.push(record),
+                    Err(issue) => break Some(IntegrityFailure { offset: at, issue }),
+                }
+                refs.push(record_ref);
+                if frames.chained {
+                    chain.advance(&raw)?;
+                }
+                frames.offset += raw.len() as u64;
+                continue;
+            }
+            Frame::Trailer(head) if macs_equal(&head, &chain.head) => break None,
+            Frame::Trailer(_) | Frame::Unterminated => IntegrityIssue::HeadMismatch,
+            Frame::End => break None,
+            Frame::Torn => IntegrityIssue::TornWrite,
+            Frame::Malformed => IntegrityIssue::MalformedRecord,
+        };
+        break Some(IntegrityFailure { offset: at, issue });
+    };
+    Ok(Scan { records, refs, failure, head: chain.head })
+}
+
+// Decrypt the single record at `at` in a chained log
+pub(crate) fn read_at(path: &str, format: Format, dek: &[u8; 32], at: RecordRef) -> std::io::Result<Record> {
+    let mut frames = Frames::open(path, format)?;
+    let raw = frames.record_at(at)?;
+    decode_record(dek, format, &raw[4..], at).map_err(|issue| integrity_error(frames.data_start + at.offset, issue))
+}
+
+// Decrypts chunk records at known positions through one open handle, so an
+// attachment can be streamed out without reopening the vault for every chunk
+pub(crate) struct ChunkReader {
+    frames: Frames,
+    format: Format,
+}
+
+impl ChunkReader {
+    pub(crate) fn open(path: &str, format: Format) -> std::io::Result<Self> {
+        Ok(Self { frames: Frames::open(path, format)?, format })
+    }
+
+    // The attachment ID, nonce and plaintext of the chunk at `at`
+    pub(crate) fn read(
+        &mut self,
+        dek: &[u8; 32],
+        at: RecordRef,
+    ) -> std::io::Result<(Uuid, StreamNonce, Zeroizing<Vec<u8>>)> {
+        let raw = self.frames.record_at(at)?;
+        let offset = self.frames.data_start + at.offset;
+        let opened =
+            open_record(dek, self.format, &raw[4..], Some(&at.prev)).map_err(|issue| integrity_error(offset, issue))?;
+        match (opened.kind, stream_nonce(&opened.nonce)) {
+            (RecordKind::Chunk, Some(nonce)) => Ok((opened.id, nonce, opened.pt)),
+            _ => Err(integrity_error(offset, IntegrityIssue::MalformedRecord)),
+        }
+    }
+}
+
+// Head stored in the trailer, or None if the log does not end in one
+pub(crate) fn trailer_head(path: &str) -> std::io::Result<Option<[u8; 32]>> {
+    let mut f = File::open(path)?;
+    let trailer_at = match f.metadata()?.len().checked_sub(TRAILER_LEN) {
+        Some(at) => at,
+        None => return Ok(None),
+    };
+    f.seek(SeekFrom::Start(trailer_at))?;
+    let mut trailer = [0u8; TRAILER_LEN as usize];
+    f.read_exact(&mut trailer)?;
+    Ok(parse_trailer(&trailer))
+}
+
+// `body` is everything after the length prefix and `at` is where it was read
+fn decode_record(dek: &[u8; 32], format: Format, body: &[u8], at: RecordRef) -> Result<Record, IntegrityIssue> {
+    let version = format.version;
+    let prev = if has_chain(version) { Some(&at.prev) } else { None };
+    let Opened { kind, id, nonce, pt } = open_record(dek, format, body, prev)?;
+    match kind {
+        RecordKind::Entry => {
+            let entry = decode_entry(version, &pt).map_err(|_| IntegrityIssue::MalformedRecord)?;
+            if entry.id != id {
+                return Err(IntegrityIssue::MalformedRecord);
+            }
+            // v1 had no tombstones; deletions were entries with every field empty
+            if version < 2 && entry.title.is_empty() && entry.username.is_empty() && entry.password.is_empty() {
+                Ok(Record::Tombstone { id, deleted_at: 0 })
+            } else {
+                Ok(Record::Entry(entry))
+            }
+        }
+        RecordKind::Tombstone => {
+            let deleted_at = match pt.len() {
+                0 => 0,
+                8 => u64::from_le_bytes(pt[..].try_into().expect("8 bytes")),
+                _ => return Err(IntegrityIssue::MalformedRecord),
+            };
+            Ok(Record::Tombstone { id, deleted_at })
+        }
+        RecordKind::Attachment => match decode_attachment(&pt) {
+            Ok(attachment) if attachment.id == id => Ok(Record::Attachment(attachment)),
+            _ => Err(IntegrityIssue::MalformedRecord),
+        },
+        RecordKind::Chunk => {
+            let nonce = stream_nonce(&nonce).ok_or(IntegrityIssue::MalformedRecord)?;
+            Ok(Record::Chunk { id, nonce, at })
+        }
+    }
+}
+
+// A record body that authenticated, before its payload is interpreted
+struct Opened {
+    kind: RecordKind,
+    id: Uuid,
+    nonce: Vec<u8>,
+    pt: Zeroizing<Vec<u8>>,
+}
+
+fn open_record(dek: &[u8; 32], format: Format, body: &[u8], prev: Option<&[u8; 32]>) -> Result<Opened, IntegrityIssue> {
+    let has_kind = format.version >= 2;
+    let (kind, rest) = if has_kind {
+        let kind = RecordKind::from_byte(body[0]).ok_or(IntegrityIssue::MalformedRecord)?;
+        (kind, &body[1..])
+    } else {
+        (RecordKind::Entry, body)
+    };
+    let id_buf: [u8; 16] = rest[..16].try_into().expect("16 bytes");
+    let (nonce, ct) = rest[16..].split_at(format.suite.nonce_len());
+
+    let item_key = derive_item_key(dek, &id_buf).map_err(|_| IntegrityIssue::AuthenticationFailed)?;
+    let aad = if has_kind { record_aad(kind, &id_buf, prev) } else { id_buf.to_vec() };
+    let pt = Zeroizing::new(
+        aead_decrypt(format.suite, &item_key, ct, nonce, &aad).map_err(|_| IntegrityIssue::AuthenticationFailed)?,
+    );
+    Ok(Opened { kind, id: Uuid::from_bytes(id_buf), nonce: nonce.to_vec(), pt })
+}
+
+// The STREAM nonce at the start of a chunk record's nonce
+fn stream_nonce(nonce: &[u8]) -> Option<StreamNonce> {
+    StreamNonce::parse(nonce.get(..12)?.try_into().ok()?)
+}
@@ -0,0 +1,94 @@
+use crate::VaultEntry;
+
+// Searched fields, most to least significant: title, tags, username, URLs, notes.
+// Passwords and custom fields are never tokenized, so no query can match them.
+const FIELD_WEIGHTS: [u32; 5] = [8, 4, 3, 2, 1];
+
+// How well a query term matched a token
+const EXACT: u32 = 4;
+const PREFIX: u32 = 3;
+const FUZZY: u32 = 2;
+
+// Lowercased word tokens of the searchable fields of one entry
+pub(crate) struct SearchTokens([Vec<String>; 5]);
+
+impl SearchTokens {
+    pub(crate) fn of(entry: &VaultEntry) -> Self {
+        Self([
+            tokenize(&entry.title),
+            entry.tags.iter().flat_map(|t| tokenize(t)).collect(),
+            tokenize(&entry.username),
+            entry.urls.iter().flat_map(|u| tokenize(u)).collect(),
+            tokenize(&entry.notes),
+        ])
+    }
+
+    // Sum over `terms` of the best weighted match for each, or None if any
+    // term matches nothing
+    pub(crate) fn score(&self, terms: &[String]) -> Option<u32> {
+        let mut total = 0;
+        for term in terms {
+            let best = self
+                .0
+                .iter()
+                .zip(FIELD_WEIGHTS)
+                .filter_map(|(tokens, weight)| {
+                    let quality = tokens.iter().filter_map(|t| match_quality(term, t)).max()?;
+                    Some(quality * weight)
+                })
+                .max()?;
+            total += best;
+        }
+        Some(total)
+    }
+}
+
+// Split on anything that is not a letter or digit, so "github.com/login"
+// yields "github", "com" and "login"
+pub(crate) fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric()).filter(|t| !t.is_empty()).map(str::to_lowercase).collect()
+}
+
+// A term matches a token exactly, as a prefix of it, or within a small edit
+// distance that grows with the term's length
+fn match_quality(term: &str, token: &str) -> Option<u32> {
+    if token == term {
+        return Some(EXACT);
+    }
+    if token.starts_with(term) {
+        return Some(PREFIX);
+    }
+    let max_edits = match term.chars().count() {
+        0..=3 => return None,
+        4..=7 => 1,
+        _ => 2,
+    };
+    within_edit_distance(term, token, max_edits).then_some(FUZZY)
+}
+
+// Edit distance (insertions, deletions, substitutions and swaps of adjacent
+// characters) of at most `max`, giving up as soon as a row exceeds it
+fn within_edit_distance(a: &str, b: &str, max: usize) -> bool {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    if a.len().abs_diff(b.len()) > max {
+        return false;
+    }
+    let mut before: Vec<usize> = Vec::new();
+    let mut prev: Vec<usize> = (0..=b.len()).collect();
+    for i in 0..a.len() {
+        let mut row = vec![i + 1; b.len() + 1];
+        for j in 0..b.len() {
+            let mut d = (prev[j] + usize::from(a[i] != b[j])).min(prev[j + 1] + 1).min(row[j] + 1);
+            if i > 0 && j > 0 && a[i] == b[j - 1] && a[i - 1] == b[j] {
+                d = d.min(before[j - 1] + 1);
+            }
+            row[j + 1] = d;
+        }
+        if row.iter().min().is_some_and(|&d| d > max) {
+            return false;
+        }
+        before = std::mem::replace(&mut prev, row);
+    }
+    prev[b.len()] <= max
+}
@@ -0,0 +1,105 @@
+mod common;
+
+use common::{remove_vault, scratch_vault, PASSWORD};
+use core_crypto::STREAM_CHUNK_SIZE;
+use std::io::ErrorKind;
+use uuid::Uuid;
+use vault_store::{RetentionPolicy, UnlockedVault, VaultEntry};
+
+fn content(len: usize) -> Vec<u8> {
+    (0..len).map(|i| (i * 31 % 251) as u8).collect()
+}
+
+fn extract(vault: &mut UnlockedVault, id: Uuid) -> std::io::Result<Vec<u8>> {
+    let mut out = Vec::new();
+    vault.extract_attachment(id, &mut out)?;
+    Ok(out)
+}
+
+fn names(vault: &mut UnlockedVault, entry_id: Uuid) -> Vec<String> {
+    vault.list_attachments(entry_id).unwrap().into_iter().map(|a| a.name).collect()
+}
+
+fn add_entry(vault: &mut UnlockedVault, title: &str) -> Uuid {
+    let entry = VaultEntry::new(title.into(), "user".into(), "pw".into());
+    vault.write_entry(&entry).unwrap();
+    entry.id
+}
+
+#[test]
+fn attachments_round_trip_at_chunk_boundaries() {
+    let path = scratch_vault("attachment-sizes");
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    let entry_id = add_entry(&mut vault, "Server");
+    let sizes = [0, 1, STREAM_CHUNK_SIZE, STREAM_CHUNK_SIZE + 1];
+    let mut added = Vec::new();
+    for size in sizes {
+        let attachment = vault.add_attachment(entry_id, &format!("{}.bin", size), &content(size)[..]).unwrap();
+        assert_eq!((attachment.entry_id, attachment.size), (entry_id, size as u64));
+        added.push(attachment.id);
+    }
+    assert_eq!(names(&mut vault, entry_id), ["0.bin", "1.bin", "65536.bin", "65537.bin"]);
+    drop(vault);
+
+    // Read back through a fresh index too
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    for (id, size) in added.iter().zip(sizes) {
+        assert_eq!(extract(&mut vault, *id).unwrap(), content(size), "{} bytes", size);
+    }
+    assert!(vault.verify_integrity().unwrap().is_intact());
+    remove_vault(&path);
+}
+
+#[test]
+fn removed_attachments_are_dropped_by_compaction() {
+    let path = scratch_vault("attachment-compaction");
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    let entry_id = add_entry(&mut vault, "Server");
+    let kept = vault.add_attachment(entry_id, "kept.bin", &content(STREAM_CHUNK_SIZE + 1)[..]).unwrap();
+    let removed = vault.add_attachment(entry_id, "removed.bin", &content(3 * STREAM_CHUNK_SIZE)[..]).unwrap();
+
+    // Chunks are re-sealed by compaction and still read back
+    vault.compact().unwrap();
+    assert_eq!(extract(&mut vault, kept.id).unwrap(), content(STREAM_CHUNK_SIZE + 1));
+    assert_eq!(extract(&mut vault, removed.id).unwrap(), content(3 * STREAM_CHUNK_SIZE));
+
+    vault.remove_attachment(removed.id).unwrap();
+    assert_eq!(names(&mut vault, entry_id), ["kept.bin"]);
+    assert_eq!(extract(&mut vault, removed.id).unwrap_err().kind(), ErrorKind::NotFound);
+    assert_eq!(vault.remove_attachment(removed.id).unwrap_err().kind(), ErrorKind::NotFound);
+    let report = vault.compact().unwrap();
+    assert!(report.bytes_reclaimed > removed.size, "{:?}", report);
+    assert_eq!(extract(&mut vault, kept.id).unwrap(), content(STREAM_CHUNK_SIZE + 1));
+    assert!(vault.verify_integrity().unwrap().is_intact());
+    remove_vault(&path);
+}
+
+// A trashed entry keeps its attachments, so restoring it brings them back;
+// they go when the entry is purged
+#[test]
+fn attachments_follow_their_entry_into_the_trash() {
+    let path = scratch_vault("attachment-trash");
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    let entry_id = add_entry(&mut vault, "Server");
+    let attachment = vault.add_attachment(entry_id, "id_ed25519", &content(100)[..]).unwrap();
+    vault.delete_entry(entry_id).unwrap();
+
+    // Nothing new can be attached to it while it is in the trash
+    let e = vault.add_attachment(entry_id, "more.bin", &content(10)[..]).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::NotFound);
+    assert_eq!(vault.add_attachment(Uuid::new_v4(), "x", &content(1)[..]).unwrap_err().kind(), ErrorKind::NotFound);
+    assert_eq!(names(&mut vault, entry_id), ["id_ed25519"]);
+    assert_eq!(extract(&mut vault, attachment.id).unwrap(), content(100));
+
+    // Compaction keeps them for as long as it keeps the entry
+    vault.compact().unwrap();
+    vault.restore_from_trash(entry_id).unwrap();
+    assert_eq!(extract(&mut vault, attachment.id).unwrap(), content(100));
+
+    vault.delete_entry(entry_id).unwrap();
+    vault.set_retention(RetentionPolicy { trash_days: 0, ..RetentionPolicy::default() });
+    vault.compact().unwrap();
+    assert!(names(&mut vault, entry_id).is_empty());
+    assert_eq!(extract(&mut vault, attachment.id).unwrap_err().kind(), ErrorKind::NotFound);
+    remove_vault(&path);
+}
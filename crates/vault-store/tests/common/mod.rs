@@ -0,0 +1,66 @@
+// Helpers shared by the integration tests. Each test binary uses only some of
+// them.
+#![allow(dead_code)]
+
+use core_crypto::{ArgonParams, CipherSuite};
+use std::path::{Path, PathBuf};
+use vault_store::{Credential, KeySlot, VaultHeader, VaultStore};
+
+pub const PASSWORD: &[u8] = b"correct horse";
+
+// Cheap enough for tests that unlock many times
+pub fn test_params() -> ArgonParams {
+    ArgonParams { mem_kib: 1024, iterations: 1, parallelism: 1 }
+}
+
+// A path in the temp dir unique to this test process, with nothing at it
+pub fn scratch_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("vault-store-{}-{}.svlt", name, std::process::id()));
+    remove_vault(&path);
+    path
+}
+
+// A new, empty vault with a single master password slot for PASSWORD
+pub fn scratch_vault(name: &str) -> PathBuf {
+    scratch_vault_with(name, test_params(), CipherSuite::default())
+}
+
+pub fn scratch_vault_with(name: &str, params: ArgonParams, suite: CipherSuite) -> PathBuf {
+    let path = scratch_path(name);
+    let mut dek = [0u8; 32];
+    getrandom::getrandom(&mut dek).unwrap();
+    let slot = KeySlot::seal(0, Credential::MasterPassword(PASSWORD), params, suite, &dek).unwrap();
+    VaultStore::create(&path, VaultHeader::new(vec![slot], false, suite), &dek).unwrap();
+    path
+}
+
+pub fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+}
+
+// Fixtures must never change, so tests that write run on a copy
+pub fn scratch_copy(name: &str) -> PathBuf {
+    let path = scratch_path(&format!("copy-{}", name.trim_end_matches(".svlt")));
+    std::fs::copy(fixture(name), &path).unwrap();
+    path
+}
+
+// The vault and its lock file
+pub fn remove_vault(path: &Path) {
+    let _ = std::fs::remove_file(path);
+    let mut lock = path.as_os_str().to_owned();
+    lock.push(".lock");
+    let _ = std::fs::remove_file(lock);
+}
+
+// Replace the header of the vault at `path`, keeping the records after it.
+// Tests use this to stand in for someone editing the file.
+pub fn write_header(path: &Path, header: &VaultHeader) {
+    let bytes = std::fs::read(path).unwrap();
+    let old_len = u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize;
+    let encoded = postcard::to_stdvec(header).unwrap();
+    let mut out = (encoded.len() as u32).to_le_bytes().to_vec();
+    out.extend_from_slice(&encoded);
+    out.extend_from_slice(&bytes[4 + old_len..]);
+    std::fs::write(path, out).unwrap();
+}
@@ -0,0 +1,98 @@
+mod common;
+
+use common::{remove_vault, scratch_vault, PASSWORD};
+use vault_store::{RetentionPolicy, UnlockedVault, VaultEntry};
+
+fn file_len(path: &std::path::Path) -> u64 {
+    std::fs::metadata(path).unwrap().len()
+}
+
+fn history(vault: &UnlockedVault, entry: &VaultEntry) -> Vec<String> {
+    vault.entry_history(entry.id).unwrap().into_iter().map(|e| e.password).collect()
+}
+
+#[test]
+fn compaction_keeps_live_versions_and_reclaims_the_rest() {
+    let path = scratch_vault("compact");
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    let mut email = VaultEntry::new("Email".into(), "alice".into(), "v1".into());
+    for password in ["v1", "v2", "v3", "v4"] {
+        email.password = password.into();
+        vault.write_entry(&email).unwrap();
+    }
+    let wiki = VaultEntry::new("Wiki".into(), "bob".into(), "wiki".into());
+    vault.write_entry(&wiki).unwrap();
+    let bank = VaultEntry::new("Bank".into(), "carol".into(), "bank".into());
+    vault.write_entry(&bank).unwrap();
+    vault.delete_entry(bank.id).unwrap();
+
+    // One older version per entry; the trash is too recent to purge
+    vault.set_retention(RetentionPolicy { history_versions: 1, trash_days: 30 });
+    let before = file_len(&path);
+    let report = vault.compact().unwrap();
+    assert_eq!(report.entries_kept, 2);
+    // Email twice, Wiki once and the trashed Bank
+    assert_eq!(report.versions_kept, 4);
+    assert!(report.bytes_reclaimed > 0);
+    assert_eq!(file_len(&path), before - report.bytes_reclaimed);
+    assert_eq!(history(&vault, &email), ["v3", "v4"]);
+    assert_eq!(history(&vault, &wiki), ["wiki"]);
+    let trash: Vec<String> = vault.list_trash().unwrap().into_iter().map(|t| t.title).collect();
+    assert_eq!(trash, ["Bank"]);
+    assert!(vault.verify_integrity().unwrap().is_intact());
+
+    let report = vault.empty_trash().unwrap();
+    assert_eq!(report.versions_kept, 3);
+    assert!(vault.list_trash().unwrap().is_empty());
+
+    vault.set_retention(RetentionPolicy { history_versions: 0, trash_days: 30 });
+    let report = vault.compact().unwrap();
+    assert_eq!((report.entries_kept, report.versions_kept), (2, 2));
+    assert!(report.bytes_reclaimed > 0);
+    assert_eq!(history(&vault, &email), ["v4"]);
+    let mut titles: Vec<String> = vault.list_entries().unwrap().into_iter().map(|(_, title)| title).collect();
+    titles.sort();
+    assert_eq!(titles, ["Email", "Wiki"]);
+    drop(vault);
+
+    // Nothing left to reclaim, and the rewritten log reopens cleanly
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    assert_eq!(vault.compact().unwrap().bytes_reclaimed, 0);
+    assert_eq!(vault.get_entry(email.id).unwrap().unwrap().password, "v4");
+    assert!(vault.verify_integrity().unwrap().is_intact());
+    drop(vault);
+    remove_vault(&path);
+}
+
+#[test]
+fn restoring_a_version_appends_it_as_the_latest() {
+    let path = scratch_vault("restore-version");
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    let mut email = VaultEntry::new("Email".into(), "alice".into(), String::new());
+    let written: Vec<String> = (1..=12).map(|i| format!("v{}", i)).collect();
+    for password in &written {
+        email.password = password.clone();
+        vault.write_entry(&email).unwrap();
+    }
+    let before = vault.entry_history(email.id).unwrap();
+
+    let restored = vault.restore_version(email.id, 2).unwrap();
+    assert_eq!(restored.password, "v3");
+    assert!(restored.modified_at >= before[11].modified_at);
+    assert_eq!(vault.get_entry(email.id).unwrap().unwrap().password, "v3");
+    // The history grows by the restored copy; nothing before it is rewritten
+    let after = history(&vault, &email);
+    assert_eq!(after.len(), before.len() + 1);
+    assert_eq!((&after[..12], after[12].as_str()), (&written[..], "v3"));
+    let e = vault.restore_version(email.id, after.len()).unwrap_err();
+    assert_eq!(e.kind(), std::io::ErrorKind::NotFound);
+
+    // The default policy keeps the latest version and the ten before it
+    vault.set_retention(RetentionPolicy::default());
+    vault.compact().unwrap();
+    let mut expected: Vec<String> = written[2..].to_vec();
+    expected.push("v3".into());
+    assert_eq!(history(&vault, &email), expected);
+    assert_eq!(vault.get_entry(email.id).unwrap().unwrap().password, "v3");
+    remove_vault(&path);
+}
@@ -0,0 +1,138 @@
+// Simulates a crash at every byte of an append and checks that the vault
+// either opens cleanly or reports a torn write that recovery repairs.
+
+mod common;
+
+use common::{remove_vault, scratch_vault, scratch_vault_with, test_params, PASSWORD};
+use core_crypto::CipherSuite;
+use std::path::Path;
+use vault_store::{Credential, IntegrityFailure, IntegrityIssue, UnlockedVault, VaultEntry, VaultError, VaultStore};
+
+fn add_entry(path: &Path, title: &str) {
+    let mut vault = UnlockedVault::unlock(path, PASSWORD).unwrap();
+    vault.write_entry(&VaultEntry::new(title.into(), "user".into(), "pw".into())).unwrap();
+}
+
+fn titles(vault: &mut UnlockedVault) -> Vec<String> {
+    vault.list_entries().unwrap().into_iter().map(|(_, title)| title).collect()
+}
+
+#[test]
+fn crash_at_every_byte_of_an_append() {
+    // Record framing depends on the suite's nonce length
+    crash_at_every_byte_with(CipherSuite::Aes256Gcm, "crash-aes");
+    crash_at_every_byte_with(CipherSuite::XChaCha20Poly1305, "crash-xchacha");
+}
+
+fn crash_at_every_byte_with(suite: CipherSuite, name: &str) {
+    let path = scratch_vault_with(name, test_params(), suite);
+    add_entry(&path, "first");
+    let before = std::fs::read(&path).unwrap();
+    add_entry(&path, "second");
+    let after = std::fs::read(&path).unwrap();
+
+    // An append overwrites the old trailer with the record and a new trailer,
+    // but writes the record's length prefix only once both are synced
+    let write_at = before.len() - 36;
+    assert_eq!(before[..write_at], after[..write_at]);
+    let mut pending = after[write_at..].to_vec();
+    pending[..4].copy_from_slice(&[0xff; 4]);
+
+    for written in 0..=pending.len() + 1 {
+        // One step past the pending bytes is the length prefix landing
+        let crashed = if written > pending.len() {
+            after.clone()
+        } else {
+            let mut crashed = [&before[..write_at], &pending[..written]].concat();
+            if write_at + written < before.len() {
+                crashed.extend_from_slice(&before[write_at + written..]);
+            }
+            crashed
+        };
+        std::fs::write(&path, &crashed).unwrap();
+
+        let mut kept = None;
+        let mut vault = match UnlockedVault::unlock(&path, PASSWORD) {
+            Ok(vault) => vault,
+            Err(e) => {
+                let torn = match VaultError::of(&e) {
+                    Some(VaultError::TornWrite(torn)) => torn,
+                    _ => panic!("{} bytes written: unexpected error {}", written, e),
+                };
+                // Only the interrupted append goes, and the report says how
+                // much before anything is dropped
+                assert_eq!(torn.offset + torn.bytes_dropped, crashed.len() as u64);
+                assert!(torn.offset >= write_at as u64);
+                let vault = UnlockedVault::unlock_and_recover(&path, Credential::MasterPassword(PASSWORD)).unwrap();
+                assert_eq!(vault.recovered(), Some(&torn));
+                kept = Some(torn.records_kept);
+                vault
+            }
+        };
+        let report = vault.verify_integrity().unwrap();
+        assert!(report.is_intact(), "{} bytes written: {:?}", written, report);
+        // Nothing of the append survives until its length prefix is written
+        let listed = titles(&mut vault);
+        let expected: &[&str] = if written > pending.len() { &["first", "second"] } else { &["first"] };
+        assert_eq!(listed, expected, "{} bytes written", written);
+        assert!(kept.is_none_or(|kept| kept == listed.len()), "{} bytes written: {:?}", written, kept);
+
+        // The recovered vault accepts new writes
+        vault.write_entry(&VaultEntry::new("third".into(), "user".into(), "pw".into())).unwrap();
+        assert_eq!(titles(&mut vault).last().map(String::as_str), Some("third"));
+        assert!(vault.verify_integrity().unwrap().is_intact());
+    }
+    remove_vault(&path);
+}
+
+#[test]
+fn corrupted_record_is_not_recovered_as_torn() {
+    let path = scratch_vault("corrupt");
+    add_entry(&path, "first");
+    add_entry(&path, "second");
+
+    // Flip the last ciphertext byte of the final record, just before the trailer
+    let mut bytes = std::fs::read(&path).unwrap();
+    let at = bytes.len() - 37;
+    bytes[at] ^= 0x01;
+    std::fs::write(&path, &bytes).unwrap();
+
+    let store = VaultStore::open(&path).unwrap();
+    let dek = store.unlock_dek(Credential::MasterPassword(PASSWORD)).unwrap();
+    assert_eq!(store.find_torn_write().unwrap(), None);
+    assert!(!store.verify_integrity(&dek).unwrap().is_intact());
+    drop(store);
+    let e = UnlockedVault::unlock(&path, PASSWORD).err().unwrap();
+    assert!(matches!(VaultError::of(&e), Some(VaultError::Integrity(_))));
+
+    let e = UnlockedVault::unlock_and_recover(&path, Credential::MasterPassword(PASSWORD)).err().unwrap();
+    assert!(matches!(VaultError::of(&e), Some(VaultError::Integrity(_))));
+    assert_eq!(std::fs::read(&path).unwrap(), bytes);
+    remove_vault(&path);
+}
+
+#[test]
+fn a_log_cut_at_a_record_boundary_is_not_recovered_as_torn() {
+    let path = scratch_vault("cut-at-boundary");
+    add_entry(&path, "first");
+    add_entry(&path, "second");
+    let boundary = std::fs::metadata(&path).unwrap().len() - 36;
+    add_entry(&path, "third");
+
+    // Drops the third record and the trailer, leaving nothing partial behind
+    let mut bytes = std::fs::read(&path).unwrap();
+    bytes.truncate(boundary as usize);
+    std::fs::write(&path, &bytes).unwrap();
+
+    let store = VaultStore::open(&path).unwrap();
+    let dek = store.unlock_dek(Credential::MasterPassword(PASSWORD)).unwrap();
+    assert_eq!(store.find_torn_write().unwrap(), None);
+    let failure = IntegrityFailure { offset: boundary, issue: IntegrityIssue::HeadMismatch };
+    assert_eq!(store.verify_integrity(&dek).unwrap().first_failure, Some(failure));
+    drop(store);
+
+    let e = UnlockedVault::unlock_and_recover(&path, Credential::MasterPassword(PASSWORD)).err().unwrap();
+    assert_eq!(VaultError::of(&e), Some(VaultError::Integrity(failure)));
+    assert_eq!(std::fs::read(&path).unwrap(), bytes);
+    remove_vault(&path);
+}
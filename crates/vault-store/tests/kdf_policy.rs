@@ -0,0 +1,31 @@
+mod common;
+
+use common::{remove_vault, scratch_vault_with, PASSWORD};
+use core_crypto::{ArgonParams, CipherSuite};
+use vault_store::{Credential, KdfPolicy, UnlockedVault, VaultEntry};
+
+#[test]
+fn unlock_raises_slot_costs_below_the_policy() {
+    let params = ArgonParams { mem_kib: 1024, iterations: 3, parallelism: 1 };
+    let path = scratch_vault_with("kdf-policy", params, CipherSuite::default());
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    vault.write_entry(&VaultEntry::new("kept".into(), "user".into(), "pw".into())).unwrap();
+    drop(vault);
+
+    let policy = KdfPolicy { min_mem_kib: 2048, min_iterations: 2, min_parallelism: 1 };
+    let credential = Credential::MasterPassword(PASSWORD);
+    let mut vault = UnlockedVault::unlock_with_policy(&path, credential, &policy).unwrap();
+    let upgrade = vault.kdf_upgrade().cloned().unwrap();
+    assert_eq!((upgrade.from.mem_kib, upgrade.from.iterations), (1024, 3));
+    // Costs already above the minimum are kept
+    assert_eq!((upgrade.to.mem_kib, upgrade.to.iterations), (2048, 3));
+    assert_eq!(vault.list_entries().unwrap().len(), 1);
+    drop(vault);
+
+    let vault = UnlockedVault::unlock_with_policy(&path, credential, &policy).unwrap();
+    assert!(vault.kdf_upgrade().is_none());
+    assert_eq!(vault.store().list_key_slots()[0].kdf_params.mem_kib, 2048);
+    assert!(vault.verify_integrity().unwrap().is_intact());
+    drop(vault);
+    remove_vault(&path);
+}
@@ -0,0 +1,124 @@
+mod common;
+
+use common::{remove_vault, scratch_path, scratch_vault, test_params, write_header, PASSWORD};
+use core_crypto::{encode_recovery_key, generate_keyfile, parse_recovery_key, ArgonParams, CipherSuite, CryptoError};
+use vault_store::{Credential, KeySlot, SlotType, UnlockedVault, VaultEntry, VaultHeader, VaultStore};
+
+#[test]
+fn a_corrupt_slot_does_not_lock_out_the_others() {
+    let path = scratch_vault("corrupt-slot");
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    vault.write_entry(&VaultEntry::new("Email".into(), "alice".into(), "pw".into())).unwrap();
+    vault.add_key_slot(Credential::MasterPassword(b"second password"), test_params()).unwrap();
+    drop(vault);
+
+    // Params Argon2 refuses, in the slot that is tried first, signed as if
+    // they had been written that way
+    let mut store = VaultStore::open(&path).unwrap();
+    let dek = store.unlock_dek(Credential::MasterPassword(PASSWORD)).unwrap();
+    store.header.key_slots[0].kdf_params.mem_kib = 0;
+    store.header.sign(&dek).unwrap();
+    write_header(&path, &store.header);
+    drop(store);
+
+    let mut vault = UnlockedVault::unlock(&path, b"second password").unwrap();
+    assert_eq!(vault.list_entries().unwrap().len(), 1);
+    drop(vault);
+    // A wrong password is still just a wrong password
+    let e = UnlockedVault::unlock(&path, b"wrong password").err().unwrap();
+    assert!(e.to_string().contains("decryption failure"), "{}", e);
+    remove_vault(&path);
+}
+
+#[test]
+fn recovery_keys_round_trip_and_reject_typos() {
+    let key = [7u8; 32];
+    let printed = encode_recovery_key(&key);
+    let groups: Vec<&str> = printed.split('-').collect();
+    assert_eq!(groups.len(), 7);
+    assert!(groups.iter().all(|g| g.len() == 8));
+    assert_eq!(parse_recovery_key(&printed), Ok(key));
+    // Case and spacing as someone might type it back in
+    assert_eq!(parse_recovery_key(&printed.to_lowercase().replace('-', " ")), Ok(key));
+
+    // One character off fails the checksum rather than yielding another key
+    let mut typo = printed.into_bytes();
+    typo[3] = if typo[3] == b'A' { b'B' } else { b'A' };
+    let typo = String::from_utf8(typo).unwrap();
+    assert_eq!(parse_recovery_key(&typo), Err(CryptoError::MalformedInput));
+    assert_eq!(parse_recovery_key("AAAA-BBBB"), Err(CryptoError::MalformedInput));
+}
+
+#[test]
+fn recovery_key_unlocks_after_the_password_is_lost() {
+    let path = scratch_vault("recovery-key");
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    let entry = VaultEntry::new("Email".into(), "alice".into(), "pw".into());
+    vault.write_entry(&entry).unwrap();
+    let first = vault.generate_recovery_key().unwrap();
+    let second = vault.generate_recovery_key().unwrap();
+    let types: Vec<SlotType> = vault.store().list_key_slots().iter().map(|s| s.slot_type).collect();
+    assert_eq!(types, [SlotType::MasterPassword, SlotType::RecoveryKey]);
+    drop(vault);
+
+    assert!(UnlockedVault::unlock(&path, b"forgotten").is_err());
+    // Issuing a new key retires the old one
+    assert!(UnlockedVault::unlock_with_recovery_key(&path, &first).is_err());
+    let mut vault = UnlockedVault::unlock_with_recovery_key(&path, &second).unwrap();
+    assert_eq!(vault.get_entry(entry.id).unwrap().unwrap().password, "pw");
+
+    // From there a new master password can be enrolled
+    vault.add_key_slot(Credential::MasterPassword(b"new password"), test_params()).unwrap();
+    drop(vault);
+    let mut vault = UnlockedVault::unlock(&path, b"new password").unwrap();
+    assert_eq!(vault.list_entries().unwrap().len(), 1);
+    drop(vault);
+    remove_vault(&path);
+}
+
+#[test]
+fn keyfile_vaults_need_the_password_and_the_right_keyfile() {
+    let path = scratch_path("keyfile");
+    let keyfile = generate_keyfile();
+    let mut dek = [0u8; 32];
+    getrandom::getrandom(&mut dek).unwrap();
+    let suite = CipherSuite::default();
+    let credential = Credential::password(PASSWORD, Some(&keyfile));
+    let slot = KeySlot::seal(0, credential, test_params(), suite, &dek).unwrap();
+    VaultStore::create(&path, VaultHeader::new(vec![slot], true, suite), &dek).unwrap();
+
+    let e = UnlockedVault::unlock(&path, PASSWORD).err().unwrap();
+    assert!(e.to_string().contains("requires a keyfile"), "{}", e);
+    let other = generate_keyfile();
+    assert!(UnlockedVault::unlock_with(&path, Credential::password(PASSWORD, Some(&other))).is_err());
+    assert!(UnlockedVault::unlock_with(&path, Credential::password(b"wrong", Some(&keyfile))).is_err());
+
+    let mut vault = UnlockedVault::unlock_with(&path, credential).unwrap();
+    vault.write_entry(&VaultEntry::new("Email".into(), "alice".into(), "pw".into())).unwrap();
+    // New password slots must take the keyfile too
+    assert!(vault.add_key_slot(Credential::MasterPassword(b"no keyfile"), test_params()).is_err());
+    drop(vault);
+    let mut vault = UnlockedVault::unlock_with(&path, credential).unwrap();
+    assert_eq!(vault.list_entries().unwrap().len(), 1);
+    drop(vault);
+    remove_vault(&path);
+}
+
+#[test]
+fn a_changed_password_keeps_the_costs_of_its_own_slot() {
+    let path = scratch_vault("passwd-params");
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    let stronger = ArgonParams { mem_kib: 2048, iterations: 2, parallelism: 1 };
+    let second = vault.add_key_slot(Credential::MasterPassword(b"second password"), stronger).unwrap();
+
+    let old = Credential::MasterPassword(b"second password");
+    vault.change_master_password(old, Credential::MasterPassword(b"changed"), None).unwrap();
+    let slots = vault.store().list_key_slots();
+    let params = |id: u32| slots.iter().find(|s| s.id == id).map(|s| ArgonParams::from(s.kdf_params.clone()));
+    assert_eq!(params(second), Some(stronger));
+    assert_eq!(params(0), Some(test_params()));
+    drop(vault);
+    UnlockedVault::unlock(&path, b"changed").unwrap();
+    UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    remove_vault(&path);
+}
@@ -0,0 +1,64 @@
+// Another process holding the vault lock is simulated by locking the lock file
+// through a separate handle, which conflicts just like a second process would.
+
+mod common;
+
+use common::{remove_vault, scratch_vault, PASSWORD};
+use std::fs::{File, OpenOptions};
+use std::path::Path;
+use std::time::{Duration, Instant};
+use vault_store::{UnlockedVault, VaultEntry, VaultError, VaultStore};
+
+fn lock_file(vault: &Path) -> File {
+    let mut path = vault.as_os_str().to_owned();
+    path.push(".lock");
+    OpenOptions::new().read(true).write(true).open(path).unwrap()
+}
+
+fn is_locked_error(e: &std::io::Error) -> bool {
+    VaultError::of(e) == Some(VaultError::VaultLocked)
+}
+
+#[test]
+fn writes_wait_for_readers_and_time_out() {
+    let path = scratch_vault("lock-write");
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    vault.set_lock_timeout(Duration::from_millis(100));
+    let entry = VaultEntry::new("title".into(), "user".into(), "pw".into());
+
+    let other = lock_file(&path);
+    other.lock_shared().unwrap();
+    // Reads share the lock, writes need it to themselves
+    assert!(vault.list_entries().unwrap().is_empty());
+    let started = Instant::now();
+    let e = vault.write_entry(&entry).unwrap_err();
+    assert!(is_locked_error(&e), "{}", e);
+    assert!(started.elapsed() >= Duration::from_millis(100));
+
+    other.unlock().unwrap();
+    vault.write_entry(&entry).unwrap();
+    assert_eq!(vault.list_entries().unwrap().len(), 1);
+    remove_vault(&path);
+}
+
+#[test]
+fn reads_wait_for_writers() {
+    let path = scratch_vault("lock-read");
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    vault.set_lock_timeout(Duration::from_millis(50));
+
+    let other = lock_file(&path);
+    other.lock().unwrap();
+    assert!(is_locked_error(&vault.list_entries().unwrap_err()));
+    assert!(is_locked_error(&VaultStore::open_with_lock_timeout(&path, Duration::ZERO).err().unwrap()));
+
+    // A writer that finishes within the timeout only delays the read
+    vault.set_lock_timeout(Duration::from_secs(5));
+    let release = std::thread::spawn(move || {
+        std::thread::sleep(Duration::from_millis(100));
+        drop(other);
+    });
+    assert!(vault.list_entries().unwrap().is_empty());
+    release.join().unwrap();
+    remove_vault(&path);
+}
@@ -0,0 +1,195 @@
+// Golden vaults in every past format version, migrated to the current one.
+// The fixtures were all generated at once, when migration was added, rather
+// than as each format shipped. For each version a throwaway program was
+// built against a git worktree of the last commit that still wrote it (v1
+// dd836b9, v2 47253d9, v3 a4c4780, v4 d975b13, v5 318a370, v6 4a8a7b2, v7
+// e678fdb, v8 0217f53, v9 and v9-xchacha 4988be9). It wrote the same history
+// with the password "fixture password": Email written and then edited, Bank
+// written and then deleted, Wiki written. From v8 on, Email also has a
+// readme.txt attachment. The files must never be regenerated.
+
+mod common;
+
+use common::{remove_vault, scratch_copy, test_params};
+use vault_store::{
+    Credential, KeySlot, UnlockOptions, UnlockedVault, VaultEntry, VaultError, VaultStore, FORMAT_VERSION,
+};
+
+const PASSWORD: &[u8] = b"fixture password";
+
+fn readme() -> Vec<u8> {
+    (0..1000).map(|i| b"attached to Email\n"[i % 18]).collect()
+}
+
+fn check_contents(vault: &mut UnlockedVault, version: u16) {
+    let mut titles: Vec<String> = vault.list_entries().unwrap().into_iter().map(|(_, title)| title).collect();
+    titles.sort();
+    assert_eq!(titles, ["Email", "Wiki"], "v{}", version);
+
+    let email_id = vault.search("Email").unwrap()[0];
+    let email = vault.get_entry(email_id).unwrap().unwrap();
+    assert_eq!((email.username.as_str(), email.password.as_str()), ("alice", "second-password"), "v{}", version);
+    let history: Vec<String> = vault.entry_history(email_id).unwrap().into_iter().map(|e| e.password).collect();
+    assert_eq!(history, ["first-password", "second-password"], "v{}", version);
+    if version >= 3 {
+        check_v3_fields(&email);
+    }
+
+    let trash: Vec<String> = vault.list_trash().unwrap().into_iter().map(|t| t.title).collect();
+    assert_eq!(trash, ["Bank"], "v{}", version);
+
+    let attachments = vault.list_attachments(email_id).unwrap();
+    if version >= 8 {
+        assert_eq!(attachments.len(), 1, "v{}", version);
+        let mut out = Vec::new();
+        vault.extract_attachment(attachments[0].id, &mut out).unwrap();
+        assert_eq!((attachments[0].name.as_str(), out), ("readme.txt", readme()), "v{}", version);
+    } else {
+        assert!(attachments.is_empty(), "v{}", version);
+    }
+}
+
+fn check_v3_fields(email: &VaultEntry) {
+    assert_eq!(email.urls, ["https://mail.example.com"]);
+    assert_eq!(email.notes, "fixture notes");
+    assert_eq!(email.tags, ["personal"]);
+    assert_eq!(email.custom_fields.len(), 1);
+    assert_eq!((email.custom_fields[0].name.as_str(), email.custom_fields[0].concealed), ("pin", true));
+}
+
+#[test]
+fn every_historical_version_migrates_to_the_current_format() {
+    for version in 1..FORMAT_VERSION {
+        let name = format!("v{}.svlt", version);
+        let path = scratch_copy(&name);
+        let original = std::fs::read(&path).unwrap();
+
+        let mut store = VaultStore::open(&path).unwrap();
+        assert_eq!(store.header.version, version);
+        let dek = store.unlock_dek(Credential::MasterPassword(PASSWORD)).unwrap();
+
+        // A dry run reports every step but leaves the file alone
+        let report = store.migrate(&dek, true).unwrap();
+        assert!(report.dry_run);
+        assert_eq!((report.from_version, report.to_version), (version, FORMAT_VERSION));
+        let steps: Vec<(u16, u16)> = report.steps.iter().map(|s| (s.from, s.to)).collect();
+        let expected: Vec<(u16, u16)> = (version..FORMAT_VERSION).map(|v| (v, v + 1)).collect();
+        assert_eq!(steps, expected, "v{}", version);
+        assert_eq!(std::fs::read(&path).unwrap(), original, "v{} dry run wrote to the vault", version);
+        assert_eq!(store.header.version, version);
+
+        let applied = store.migrate(&dek, false).unwrap();
+        assert_eq!((applied.records, applied.bytes_after), (report.records, report.bytes_after), "v{}", version);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), applied.bytes_after);
+        drop(store);
+
+        let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+        assert_eq!(vault.store().header.version, FORMAT_VERSION);
+        assert!(vault.verify_integrity().unwrap().is_intact(), "v{}", version);
+        check_contents(&mut vault, version);
+        drop(vault);
+        remove_vault(&path);
+    }
+}
+
+#[test]
+fn current_version_fixtures_need_no_migration() {
+    for name in ["v9.svlt", "v9-xchacha.svlt"] {
+        let path = scratch_copy(name);
+        let original = std::fs::read(&path).unwrap();
+        let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+        assert!(vault.verify_integrity().unwrap().is_intact(), "{}", name);
+        check_contents(&mut vault, FORMAT_VERSION);
+        drop(vault);
+
+        let mut store = VaultStore::open(&path).unwrap();
+        let dek = store.unlock_dek(Credential::MasterPassword(PASSWORD)).unwrap();
+        assert!(store.migrate(&dek, false).unwrap().steps.is_empty());
+        assert_eq!(std::fs::read(&path).unwrap(), original, "{}", name);
+        remove_vault(&path);
+    }
+}
+
+#[test]
+fn unsigned_formats_are_only_migrated_when_asked() {
+    for version in 1..FORMAT_VERSION {
+        let path = scratch_copy(&format!("v{}.svlt", version));
+        let original = std::fs::read(&path).unwrap();
+        match UnlockedVault::unlock(&path, PASSWORD) {
+            // Signed formats are checked against the DEK and upgraded on the way
+            Ok(mut vault) => {
+                assert!(version >= 6, "v{} was migrated without being asked", version);
+                check_contents(&mut vault, version);
+            }
+            Err(e) => {
+                assert_eq!(VaultError::of(&e), Some(VaultError::UnsignedHeader { version }), "v{}", version);
+                assert_eq!(std::fs::read(&path).unwrap(), original, "v{}", version);
+                let mut vault = UnlockedVault::unlock_and_migrate(&path, Credential::MasterPassword(PASSWORD)).unwrap();
+                assert_eq!(vault.store().header.version, FORMAT_VERSION);
+                check_contents(&mut vault, version);
+            }
+        }
+        remove_vault(&path);
+    }
+}
+
+#[test]
+fn an_unsigned_format_with_a_torn_write_is_recovered_and_migrated() {
+    let path = scratch_copy("v5.svlt");
+    let mut bytes = std::fs::read(&path).unwrap();
+    // A length prefix for a record that never made it to disk
+    bytes.extend_from_slice(&1000u32.to_le_bytes());
+    bytes.extend_from_slice(&[0u8; 10]);
+    std::fs::write(&path, &bytes).unwrap();
+
+    // Each of these asks for only one of the two, and changes nothing
+    let password = Credential::MasterPassword(PASSWORD);
+    let e = UnlockedVault::unlock_and_recover(&path, password).err().unwrap();
+    assert_eq!(VaultError::of(&e), Some(VaultError::UnsignedHeader { version: 5 }));
+    let e = UnlockedVault::unlock_and_migrate(&path, password).err().unwrap();
+    assert!(matches!(VaultError::of(&e), Some(VaultError::TornWrite(torn)) if torn.bytes_dropped == 14));
+    assert_eq!(std::fs::read(&path).unwrap(), bytes);
+
+    let options = UnlockOptions { recover: true, migrate_unsigned: true, ..Default::default() };
+    let mut vault = UnlockedVault::unlock_with_options(&path, password, &options).unwrap();
+    assert_eq!(vault.recovered().map(|torn| torn.bytes_dropped), Some(14));
+    assert_eq!(vault.store().header.version, FORMAT_VERSION);
+    assert!(vault.verify_integrity().unwrap().is_intact());
+    check_contents(&mut vault, 5);
+    drop(vault);
+    remove_vault(&path);
+}
+
+#[test]
+fn a_header_signed_with_another_key_is_not_migrated() {
+    // A v8 header with an extra slot, re-signed with someone else's key and
+    // written in the v6-v8 layout, which has no cipher suite
+    let path = scratch_copy("v8.svlt");
+    let mut store = VaultStore::open(&path).unwrap();
+    let dek = store.unlock_dek(Credential::MasterPassword(PASSWORD)).unwrap();
+    let foreign = [9u8; 32];
+    let suite = store.header.cipher_suite;
+    let mut header = store.header.clone();
+    header.key_slots.push(KeySlot::seal(7, Credential::MasterPassword(b"x"), test_params(), suite, &foreign).unwrap());
+    header.sign(&foreign).unwrap();
+    let v8_layout =
+        (header.magic, header.version, &header.key_slots, header.keyfile_required, header.verify_key, &header.signature);
+    let encoded = postcard::to_stdvec(&v8_layout).unwrap();
+    let bytes = std::fs::read(&path).unwrap();
+    let old_len = u32::from_le_bytes(bytes[..4].try_into().unwrap()) as usize;
+    let mut tampered = (encoded.len() as u32).to_le_bytes().to_vec();
+    tampered.extend_from_slice(&encoded);
+    tampered.extend_from_slice(&bytes[4 + old_len..]);
+    std::fs::write(&path, &tampered).unwrap();
+
+    // The signature itself checks out, so the header is read as it was written
+    store = VaultStore::open(&path).unwrap();
+    assert_eq!((store.header.version, store.header.key_slots.len()), (8, header.key_slots.len()));
+    let e = store.migrate(&dek, false).unwrap_err();
+    assert_eq!(VaultError::of(&e), Some(VaultError::HeaderTampered));
+    drop(store);
+    let e = UnlockedVault::unlock(&path, PASSWORD).err().unwrap();
+    assert_eq!(VaultError::of(&e), Some(VaultError::HeaderTampered));
+    assert_eq!(std::fs::read(&path).unwrap(), tampered);
+    remove_vault(&path);
+}
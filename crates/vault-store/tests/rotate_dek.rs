@@ -0,0 +1,52 @@
+mod common;
+
+use common::{remove_vault, scratch_vault, PASSWORD};
+use vault_store::{Credential, SlotType, UnlockedVault, VaultEntry, VaultStore};
+
+#[test]
+fn rotation_re_encrypts_everything_under_a_new_dek() {
+    let path = scratch_vault("rotate");
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    let mut email = VaultEntry::new("Email".into(), "alice".into(), "first".into());
+    vault.write_entry(&email).unwrap();
+    email.password = "second".into();
+    vault.write_entry(&email).unwrap();
+    vault.write_entry(&VaultEntry::new("Wiki".into(), "bob".into(), "wiki".into())).unwrap();
+    let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+    let attachment = vault.add_attachment(email.id, "key.pem", content.as_slice()).unwrap();
+    let recovery_key = vault.generate_recovery_key().unwrap();
+    let old_dek = VaultStore::open(&path).unwrap().unlock_dek(Credential::MasterPassword(PASSWORD)).unwrap();
+
+    let mut last = (0, 0);
+    vault.rotate_dek(Credential::MasterPassword(PASSWORD), |done, total| last = (done, total)).unwrap();
+    assert!(last.1 > 0 && last.0 == last.1, "{:?}", last);
+    // The session carries on under the new DEK
+    assert_eq!(vault.get_entry(email.id).unwrap().unwrap().password, "second");
+    drop(vault);
+
+    // The old DEK opens nothing any more
+    let store = VaultStore::open(&path).unwrap();
+    assert!(store.read_all_entries(&old_dek).is_err());
+    assert!(store.header.verify_signer(&old_dek).is_err());
+    let new_dek = store.unlock_dek(Credential::MasterPassword(PASSWORD)).unwrap();
+    assert_ne!(*new_dek, *old_dek);
+
+    // Only the rotating credential's slot is left
+    let slots: Vec<SlotType> = store.list_key_slots().iter().map(|s| s.slot_type).collect();
+    assert_eq!(slots, [SlotType::MasterPassword]);
+    assert!(UnlockedVault::unlock_with_recovery_key(&path, &recovery_key).is_err());
+    drop(store);
+
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    assert!(vault.verify_integrity().unwrap().is_intact());
+    assert_eq!(vault.list_entries().unwrap().len(), 2);
+    let history: Vec<String> = vault.entry_history(email.id).unwrap().into_iter().map(|e| e.password).collect();
+    assert_eq!(history, ["first", "second"]);
+    let attachments = vault.list_attachments(email.id).unwrap();
+    assert_eq!(attachments.len(), 1);
+    let mut out = Vec::new();
+    vault.extract_attachment(attachment.id, &mut out).unwrap();
+    assert_eq!(out, content);
+    drop(vault);
+    remove_vault(&path);
+}
@@ -0,0 +1,91 @@
+mod common;
+
+use common::{remove_vault, scratch_vault, PASSWORD};
+use uuid::Uuid;
+use vault_store::{CustomField, UnlockedVault, VaultEntry};
+
+fn add(vault: &mut UnlockedVault, title: &str, edit: impl FnOnce(&mut VaultEntry)) -> Uuid {
+    let mut entry = VaultEntry::new(title.into(), "user".into(), "pw".into());
+    edit(&mut entry);
+    vault.write_entry(&entry).unwrap();
+    entry.id
+}
+
+#[test]
+fn typos_and_prefixes_match() {
+    let path = scratch_vault("search-fuzzy");
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    let github = add(&mut vault, "GitHub", |_| {});
+    add(&mut vault, "Bank", |_| {});
+
+    for query in ["github", "GITHUB", "gihtub", "githbu", "git", "gi"] {
+        assert_eq!(vault.search(query).unwrap(), [github], "{:?}", query);
+    }
+    // Short terms have to match exactly or as a prefix
+    assert!(vault.search("gti").unwrap().is_empty());
+    assert!(vault.search("gitlab").unwrap().is_empty());
+    remove_vault(&path);
+}
+
+#[test]
+fn matches_rank_by_the_field_they_are_in() {
+    let path = scratch_vault("search-rank");
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    // Written least significant first, so log order cannot explain the ranking
+    let notes = add(&mut vault, "Notes", |e| e.notes = "see github".into());
+    let url = add(&mut vault, "Url", |e| e.urls = vec!["https://github.com".into()]);
+    let username = add(&mut vault, "Username", |e| e.username = "github".into());
+    let tag = add(&mut vault, "Tag", |e| e.tags = vec!["github".into()]);
+    let title = add(&mut vault, "GitHub", |_| {});
+
+    assert_eq!(vault.search("github").unwrap(), [title, tag, username, url, notes]);
+    // An exact match outranks a prefix in the same field
+    let prefixed = add(&mut vault, "GitHubber", |_| {});
+    assert_eq!(vault.search("github").unwrap()[..2], [title, prefixed]);
+    remove_vault(&path);
+}
+
+#[test]
+fn secrets_never_match() {
+    let path = scratch_vault("search-secrets");
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    add(&mut vault, "Email", |e| {
+        e.password = "hunter2 swordfish".into();
+        let pin = CustomField { name: "pin".into(), value: "treasure".into(), concealed: true };
+        e.custom_fields.push(pin);
+    });
+
+    for query in ["hunter2", "swordfish", "treasure", "pin"] {
+        assert!(vault.search(query).unwrap().is_empty(), "{:?}", query);
+    }
+    remove_vault(&path);
+}
+
+#[test]
+fn empty_queries_match_nothing() {
+    let path = scratch_vault("search-empty");
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    add(&mut vault, "Email", |_| {});
+
+    for query in ["", "   ", "\t\n", " - / . "] {
+        assert!(vault.search(query).unwrap().is_empty(), "{:?}", query);
+    }
+    remove_vault(&path);
+}
+
+#[test]
+fn every_word_of_a_query_has_to_match() {
+    let path = scratch_vault("search-words");
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    let work = add(&mut vault, "GitHub", |e| e.tags = vec!["work".into()]);
+    let personal = add(&mut vault, "GitHub", |e| e.tags = vec!["personal".into()]);
+    add(&mut vault, "Jira", |e| e.tags = vec!["work".into()]);
+
+    assert_eq!(vault.search("github work").unwrap(), [work]);
+    assert_eq!(vault.search("personal  GITHUB").unwrap(), [personal]);
+    assert!(vault.search("github jira").unwrap().is_empty());
+    // Deleted entries drop out of the results
+    vault.delete_entry(work).unwrap();
+    assert!(vault.search("github work").unwrap().is_empty());
+    remove_vault(&path);
+}
@@ -0,0 +1,100 @@
+// Two sessions on one vault stand in for two processes: neither sees the
+// other's changes except through the file.
+
+mod common;
+
+use common::{remove_vault, scratch_vault, test_params, PASSWORD};
+use vault_store::{Credential, SlotType, UnlockedVault, VaultEntry, VaultError};
+
+fn entry(title: &str) -> VaultEntry {
+    VaultEntry::new(title.into(), "user".into(), "pw".into())
+}
+
+#[test]
+fn header_changes_from_a_stale_session_keep_the_other_sessions_changes() {
+    let path = scratch_vault("stale-header");
+    let mut a = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    let mut b = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    let old = Credential::MasterPassword(PASSWORD);
+    let new = Credential::MasterPassword(b"new password");
+    b.change_master_password(old, new, Some(test_params())).unwrap();
+
+    // A still holds the header from before the change; writing its own
+    // header change must not bring the old password back
+    let recovery_key = a.generate_recovery_key().unwrap();
+    drop((a, b));
+    assert!(UnlockedVault::unlock(&path, PASSWORD).is_err());
+    let vault = UnlockedVault::unlock(&path, b"new password").unwrap();
+    let types: Vec<SlotType> = vault.store().list_key_slots().iter().map(|s| s.slot_type).collect();
+    assert_eq!(types, [SlotType::MasterPassword, SlotType::RecoveryKey]);
+    drop(vault);
+    UnlockedVault::unlock_with_recovery_key(&path, &recovery_key).unwrap();
+
+    // Re-sealing a slot that was re-sealed since is refused instead
+    let mut a = UnlockedVault::unlock(&path, b"new password").unwrap();
+    let mut b = UnlockedVault::unlock(&path, b"new password").unwrap();
+    let current = Credential::MasterPassword(b"new password");
+    b.change_master_password(current, Credential::MasterPassword(b"from b"), Some(test_params())).unwrap();
+    let e = a.change_master_password(current, Credential::MasterPassword(b"from a"), Some(test_params()));
+    assert_eq!(VaultError::of(&e.unwrap_err()), Some(VaultError::VaultChanged));
+    drop((a, b));
+    UnlockedVault::unlock(&path, b"from b").unwrap();
+    remove_vault(&path);
+}
+
+#[test]
+fn a_stale_session_cannot_write_after_the_dek_was_rotated() {
+    let path = scratch_vault("stale-dek");
+    let mut a = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    a.write_entry(&entry("before")).unwrap();
+    let mut b = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    b.rotate_dek(Credential::MasterPassword(PASSWORD), |_, _| {}).unwrap();
+
+    // A's DEK no longer opens the vault, so nothing sealed with it may land
+    for result in [a.write_entry(&entry("after")), a.delete_entry(entry("x").id)] {
+        assert_eq!(VaultError::of(&result.unwrap_err()), Some(VaultError::VaultChanged));
+    }
+    let e = a.add_key_slot(Credential::MasterPassword(b"second"), test_params()).unwrap_err();
+    assert_eq!(VaultError::of(&e), Some(VaultError::VaultChanged));
+    assert_eq!(VaultError::of(&a.list_entries().unwrap_err()), Some(VaultError::VaultChanged));
+    assert_eq!(VaultError::of(&a.compact().unwrap_err()), Some(VaultError::VaultChanged));
+    drop(a);
+
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    assert!(vault.verify_integrity().unwrap().is_intact());
+    let titles: Vec<String> = vault.list_entries().unwrap().into_iter().map(|(_, t)| t).collect();
+    assert_eq!(titles, ["before"]);
+    // B's session moved to the new DEK with the rotation and keeps working
+    b.write_entry(&entry("after")).unwrap();
+    drop((vault, b));
+    assert_eq!(UnlockedVault::unlock(&path, PASSWORD).unwrap().list_entries().unwrap().len(), 2);
+    remove_vault(&path);
+}
+
+#[test]
+fn reads_pick_up_what_another_session_wrote() {
+    let path = scratch_vault("other-writes");
+    let mut a = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    let mut b = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    let kept = entry("kept");
+    a.write_entry(&kept).unwrap();
+    // Builds A's index before B writes anything
+    assert_eq!(a.list_entries().unwrap().len(), 1);
+
+    let mut added = entry("Written elsewhere");
+    added.notes = "bank login".into();
+    b.write_entry(&added).unwrap();
+    b.add_attachment(added.id, "key.txt", &b"secret"[..]).unwrap();
+    b.delete_entry(kept.id).unwrap();
+
+    assert_eq!(a.list_entries().unwrap(), [(added.id, added.title.clone())]);
+    assert_eq!(a.search("bank").unwrap(), [added.id]);
+    assert_eq!(a.get_entry(added.id).unwrap().map(|e| e.title), Some(added.title.clone()));
+    assert!(a.get_entry(kept.id).unwrap().is_none());
+    assert_eq!(a.list_attachments(added.id).unwrap().len(), 1);
+
+    // And A's own writes land after B's, without losing them
+    a.write_entry(&entry("from a")).unwrap();
+    assert_eq!(b.list_entries().unwrap().len(), 2);
+    remove_vault(&path);
+}
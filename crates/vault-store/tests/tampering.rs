@@ -0,0 +1,123 @@
+mod common;
+
+use common::{remove_vault, scratch_vault, test_params, write_header, PASSWORD};
+use std::path::{Path, PathBuf};
+use vault_store::{
+    Credential, IntegrityFailure, IntegrityIssue, KdfPolicy, KeySlot, UnlockedVault, VaultEntry, VaultError, VaultStore,
+};
+
+fn is_tampered<T>(result: std::io::Result<T>) -> bool {
+    match result {
+        Ok(_) => panic!("a tampered header was accepted"),
+        Err(e) => VaultError::of(&e) == Some(VaultError::HeaderTampered),
+    }
+}
+
+// Adds a slot for someone else's password and signs the header with a key of
+// their own, so the signature is valid but not ours. Returns the real DEK.
+fn tamper(path: &Path) -> [u8; 32] {
+    let mut store = VaultStore::open(path).unwrap();
+    let dek = *store.unlock_dek(Credential::MasterPassword(PASSWORD)).unwrap();
+    let foreign = [9u8; 32];
+    let slot = KeySlot::seal(7, Credential::MasterPassword(b"intruder"), test_params(), store.header.cipher_suite, &foreign);
+    store.header.key_slots.push(slot.unwrap());
+    store.header.sign(&foreign).unwrap();
+    write_header(path, &store.header);
+    dek
+}
+
+#[test]
+fn a_header_signed_with_another_key_is_never_rewritten() {
+    let path = scratch_vault("tampered-header");
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    vault.write_entry(&VaultEntry::new("Email".into(), "alice".into(), "pw".into())).unwrap();
+    drop(vault);
+    let dek = tamper(&path);
+    let tampered = std::fs::read(&path).unwrap();
+
+    // The signature itself checks out, so opening works; using it does not
+    let mut store = VaultStore::open(&path).unwrap();
+    let password = Credential::MasterPassword(PASSWORD);
+    assert!(is_tampered(UnlockedVault::unlock(&path, PASSWORD)));
+    assert!(is_tampered(store.unlock_dek(password)));
+    let new = Credential::MasterPassword(b"new");
+    assert!(is_tampered(store.change_master_password(password, new, Some(test_params()))));
+    assert!(is_tampered(store.rotate_dek(password, |_, _| {})));
+    let policy = KdfPolicy { min_mem_kib: 2048, min_iterations: 2, min_parallelism: 1 };
+    assert!(is_tampered(store.upgrade_kdf(password, &policy)));
+
+    // Someone still holding the DEK from before must not bless it either
+    assert!(is_tampered(store.add_key_slot(&dek, Credential::MasterPassword(b"second"), test_params())));
+    assert!(is_tampered(store.remove_key_slot(&dek, 7)));
+    assert!(is_tampered(store.compact(&dek)));
+    assert_eq!(std::fs::read(&path).unwrap(), tampered);
+    remove_vault(&path);
+}
+
+// A vault with three entries, split into what comes before the records, each
+// record, and the trailer, along with the file offset of each record
+struct Split {
+    path: PathBuf,
+    start: Vec<u8>,
+    records: Vec<Vec<u8>>,
+    offsets: Vec<u64>,
+    trailer: Vec<u8>,
+}
+
+fn three_records(name: &str) -> Split {
+    let path = scratch_vault(name);
+    let mut ends = vec![std::fs::metadata(&path).unwrap().len() as usize - 36];
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    for title in ["one", "two", "three"] {
+        vault.write_entry(&VaultEntry::new(title.into(), "user".into(), "pw".into())).unwrap();
+        ends.push(std::fs::metadata(&path).unwrap().len() as usize - 36);
+    }
+    drop(vault);
+    let bytes = std::fs::read(&path).unwrap();
+    Split {
+        start: bytes[..ends[0]].to_vec(),
+        records: ends.windows(2).map(|w| bytes[w[0]..w[1]].to_vec()).collect(),
+        offsets: ends.iter().map(|&end| end as u64).collect(),
+        trailer: bytes[ends[3]..].to_vec(),
+        path,
+    }
+}
+
+// Writes the records in `order` and checks that the first one out of place
+// is reported at `offset`, both by `verify_integrity` and by unlocking
+fn assert_caught(split: &Split, order: &[usize], offset: u64) {
+    let mut bytes = split.start.clone();
+    for &i in order {
+        bytes.extend_from_slice(&split.records[i]);
+    }
+    bytes.extend_from_slice(&split.trailer);
+    std::fs::write(&split.path, &bytes).unwrap();
+
+    let failure = IntegrityFailure { offset, issue: IntegrityIssue::AuthenticationFailed };
+    let store = VaultStore::open(&split.path).unwrap();
+    let dek = store.unlock_dek(Credential::MasterPassword(PASSWORD)).unwrap();
+    assert_eq!(store.verify_integrity(&dek).unwrap().first_failure, Some(failure), "{:?}", order);
+    drop(store);
+    let e = UnlockedVault::unlock(&split.path, PASSWORD).err().unwrap();
+    assert_eq!(VaultError::of(&e), Some(VaultError::Integrity(failure)), "{:?}", order);
+    assert_eq!(std::fs::read(&split.path).unwrap(), bytes);
+    remove_vault(&split.path);
+}
+
+#[test]
+fn a_dropped_record_is_caught() {
+    let split = three_records("dropped-record");
+    assert_caught(&split, &[0, 2], split.offsets[1]);
+}
+
+#[test]
+fn reordered_records_are_caught() {
+    let split = three_records("reordered-records");
+    assert_caught(&split, &[0, 2, 1], split.offsets[1]);
+}
+
+#[test]
+fn a_replayed_record_is_caught() {
+    let split = three_records("replayed-record");
+    assert_caught(&split, &[0, 1, 1, 2], split.offsets[2]);
+}
@@ -0,0 +1,131 @@
+mod common;
+
+use common::{remove_vault, scratch_vault, PASSWORD};
+use std::time::{SystemTime, UNIX_EPOCH};
+use vault_store::{Credential, RetentionPolicy, UnlockedVault, VaultEntry, VaultStore};
+
+fn unix_now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs()
+}
+
+fn titles(vault: &mut UnlockedVault) -> Vec<String> {
+    vault.list_entries().unwrap().into_iter().map(|(_, title)| title).collect()
+}
+
+fn trash(vault: &UnlockedVault) -> Vec<String> {
+    vault.list_trash().unwrap().into_iter().map(|t| t.title).collect()
+}
+
+#[test]
+fn trashed_entries_are_listed_and_restored_once() {
+    let path = scratch_vault("trash-restore");
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    let mut email = VaultEntry::new("Email".into(), "alice".into(), "first".into());
+    vault.write_entry(&email).unwrap();
+    email.password = "second".into();
+    vault.write_entry(&email).unwrap();
+    let wiki = VaultEntry::new("Wiki".into(), "bob".into(), "wiki".into());
+    vault.write_entry(&wiki).unwrap();
+    assert!(vault.list_trash().unwrap().is_empty());
+
+    let before = unix_now();
+    vault.delete_entry(email.id).unwrap();
+    let listed = vault.list_trash().unwrap();
+    assert_eq!((listed.len(), listed[0].id, listed[0].title.as_str()), (1, email.id, "Email"));
+    assert!((before..=unix_now()).contains(&listed[0].deleted_at));
+    assert_eq!(titles(&mut vault), ["Wiki"]);
+
+    // The last version comes back, and its history with it
+    let restored = vault.restore_from_trash(email.id).unwrap();
+    assert_eq!(restored.password, "second");
+    assert_eq!(vault.get_entry(email.id).unwrap().unwrap().password, "second");
+    assert_eq!(vault.entry_history(email.id).unwrap().len(), 3);
+    assert_eq!(titles(&mut vault), ["Wiki", "Email"]);
+    assert!(vault.list_trash().unwrap().is_empty());
+
+    for id in [email.id, wiki.id] {
+        let e = vault.restore_from_trash(id).unwrap_err();
+        assert_eq!((e.kind(), e.to_string().as_str()), (std::io::ErrorKind::NotFound, "entry is not in the trash"));
+    }
+    assert!(vault.verify_integrity().unwrap().is_intact());
+    remove_vault(&path);
+}
+
+#[test]
+fn compaction_purges_what_was_trashed_before_the_cutoff() {
+    let path = scratch_vault("trash-purge");
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    for title in ["Email", "Wiki", "Bank"] {
+        vault.write_entry(&VaultEntry::new(title.into(), "user".into(), "pw".into())).unwrap();
+    }
+    let ids: Vec<_> = vault.list_entries().unwrap().into_iter().map(|(id, _)| id).collect();
+    vault.delete_entry(ids[0]).unwrap();
+    vault.delete_entry(ids[2]).unwrap();
+    let deleted_at: Vec<u64> = vault.list_trash().unwrap().iter().map(|t| t.deleted_at).collect();
+    let (first, last) = (*deleted_at.iter().min().unwrap(), *deleted_at.iter().max().unwrap());
+    drop(vault);
+
+    // Deleted at or after the cutoff stays; once it is later, it is purged
+    let store = VaultStore::open(&path).unwrap();
+    let dek = store.unlock_dek(Credential::MasterPassword(PASSWORD)).unwrap();
+    let report = store.compact_with(&dek, usize::MAX, first).unwrap();
+    assert_eq!((report.entries_kept, report.versions_kept), (1, 3));
+    let report = store.compact_with(&dek, usize::MAX, last + 1).unwrap();
+    assert_eq!((report.entries_kept, report.versions_kept), (1, 1));
+    drop(store);
+
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    assert!(vault.list_trash().unwrap().is_empty());
+    assert_eq!(titles(&mut vault), ["Wiki"]);
+    assert!(vault.verify_integrity().unwrap().is_intact());
+    remove_vault(&path);
+}
+
+#[test]
+fn keeping_trash_for_no_days_purges_all_of_it() {
+    let path = scratch_vault("trash-zero-days");
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    let email = VaultEntry::new("Email".into(), "alice".into(), "pw".into());
+    vault.write_entry(&email).unwrap();
+    vault.delete_entry(email.id).unwrap();
+
+    vault.set_retention(RetentionPolicy { trash_days: 1, ..RetentionPolicy::default() });
+    vault.compact().unwrap();
+    assert_eq!(trash(&vault), ["Email"]);
+
+    // Even what was deleted this second
+    vault.set_retention(RetentionPolicy { trash_days: 0, ..RetentionPolicy::default() });
+    assert_eq!(vault.compact().unwrap().versions_kept, 0);
+    assert!(trash(&vault).is_empty());
+    remove_vault(&path);
+}
+
+// Only a tombstone deletes: an entry with every field empty is still an entry
+#[test]
+fn a_blank_entry_stays_live() {
+    let path = scratch_vault("trash-blank");
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    let blank = VaultEntry::new(String::new(), String::new(), String::new());
+    vault.write_entry(&blank).unwrap();
+    assert_eq!(vault.list_entries().unwrap(), [(blank.id, String::new())]);
+    drop(vault);
+
+    // Read back from the file, by every way of reading entries
+    let store = VaultStore::open(&path).unwrap();
+    let dek = store.unlock_dek(Credential::MasterPassword(PASSWORD)).unwrap();
+    assert_eq!(store.list_entries(&dek).unwrap(), [(blank.id, String::new())]);
+    let all: Vec<_> = store.read_all_entries(&dek).unwrap().into_iter().map(|e| e.id).collect();
+    assert_eq!(all, [blank.id]);
+    assert!(store.list_trash(&dek).unwrap().is_empty());
+    store.compact(&dek).unwrap();
+    drop(store);
+
+    let mut vault = UnlockedVault::unlock(&path, PASSWORD).unwrap();
+    let read = vault.get_entry(blank.id).unwrap().unwrap();
+    assert!(read.title.is_empty() && read.username.is_empty() && read.password.is_empty());
+    vault.delete_entry(blank.id).unwrap();
+    assert!(vault.get_entry(blank.id).unwrap().is_none());
+    assert!(vault.list_entries().unwrap().is_empty());
+    assert_eq!(vault.list_trash().unwrap()[0].id, blank.id);
+    remove_vault(&path);
+}